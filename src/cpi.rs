@@ -0,0 +1,171 @@
+//! Helpers for programs entering races on behalf of their users through CPI
+//!
+//! An invoking program must be on the allowlist of the program config, see
+//! `RaceInstruction::SetAllowedPrograms`. It proves its identity by signing with its CPI
+//! authority, the PDA of [CPI_AUTHORITY] under its own program id, see
+//! `state::find_cpi_authority_address`. The player signs as well and may be any PDA of the
+//! invoking program, the same `signer_seeds` are passed to `invoke_signed` for both.
+//! Plain JoinRace and LeaveRace fail when invoked by another program.
+//!
+//! ```ignore
+//! race::cpi::join_race(
+//!     race::cpi::JoinRace {
+//!         race_program: race_program_info.clone(),
+//!         config: config_info.clone(),
+//!         caller_program: my_program_info.clone(),
+//!         cpi_authority: cpi_authority_info.clone(),
+//!         race: race_info.clone(),
+//!         player: stable_info.clone(),
+//!         entry: entry_info.clone(),
+//!         vault: vault_info.clone(),
+//!         system_program: system_program_info.clone(),
+//!         clock: clock_info.clone(),
+//!         rent: rent_info.clone(),
+//!         token_accounts: None,
+//!     },
+//!     slot,
+//!     &[&[CPI_AUTHORITY.as_bytes(), &[cpi_authority_bump]], &[b"stable", &[stable_bump]]],
+//! )?;
+//! ```
+
+use crate::instruction::{JoinRaceArgs, RaceInstruction};
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
+    program::invoke_signed,
+};
+
+/// Token accounts moving the entry fee of a race with a fee mint
+#[derive(Clone)]
+pub struct TokenAccounts<'a> {
+    /// Token account of the player, paid from on join and paid to on leave
+    pub player_token: AccountInfo<'a>,
+    /// Associated token account of the vault
+    pub vault_token: AccountInfo<'a>,
+    pub token_program: AccountInfo<'a>,
+}
+
+/// Accounts of `join_race`
+#[derive(Clone)]
+pub struct JoinRace<'a> {
+    pub race_program: AccountInfo<'a>,
+    pub config: AccountInfo<'a>,
+    /// The invoking program, must be allowed by the config
+    pub caller_program: AccountInfo<'a>,
+    /// PDA of [CPI_AUTHORITY] under the invoking program
+    pub cpi_authority: AccountInfo<'a>,
+    pub race: AccountInfo<'a>,
+    /// Pays the entry fee and the race entry rent
+    pub player: AccountInfo<'a>,
+    /// Race entry, PDA of [PREFIX, race program id, race, player]
+    pub entry: AccountInfo<'a>,
+    pub vault: AccountInfo<'a>,
+    pub system_program: AccountInfo<'a>,
+    pub clock: AccountInfo<'a>,
+    pub rent: AccountInfo<'a>,
+    /// Required when the race has a fee mint
+    pub token_accounts: Option<TokenAccounts<'a>>,
+}
+
+/// Accounts of `leave_race`
+#[derive(Clone)]
+pub struct LeaveRace<'a> {
+    pub race_program: AccountInfo<'a>,
+    pub config: AccountInfo<'a>,
+    /// The invoking program, must be allowed by the config
+    pub caller_program: AccountInfo<'a>,
+    /// PDA of [CPI_AUTHORITY] under the invoking program
+    pub cpi_authority: AccountInfo<'a>,
+    pub race: AccountInfo<'a>,
+    /// Receives the refund and the race entry rent
+    pub player: AccountInfo<'a>,
+    pub entry: AccountInfo<'a>,
+    pub vault: AccountInfo<'a>,
+    pub clock: AccountInfo<'a>,
+    /// Required when the race has a fee mint
+    pub token_accounts: Option<TokenAccounts<'a>>,
+}
+
+/// Invokes CpiJoinRace, `signer_seeds` must sign for the CPI authority and the player
+pub fn join_race(accounts: JoinRace, slot: u8, signer_seeds: &[&[&[u8]]]) -> ProgramResult {
+    let mut account_infos = vec![
+        accounts.caller_program,
+        accounts.cpi_authority,
+        accounts.config,
+        accounts.race,
+        accounts.player,
+        accounts.entry,
+        accounts.vault,
+        accounts.system_program,
+        accounts.clock,
+        accounts.rent,
+    ];
+    let mut metas = vec![
+        AccountMeta::new_readonly(*account_infos[0].key, false),
+        AccountMeta::new_readonly(*account_infos[1].key, true),
+        AccountMeta::new_readonly(*account_infos[2].key, false),
+        AccountMeta::new(*account_infos[3].key, false),
+        AccountMeta::new(*account_infos[4].key, true),
+        AccountMeta::new(*account_infos[5].key, false),
+        AccountMeta::new(*account_infos[6].key, false),
+        AccountMeta::new_readonly(*account_infos[7].key, false),
+        AccountMeta::new_readonly(*account_infos[8].key, false),
+        AccountMeta::new_readonly(*account_infos[9].key, false),
+    ];
+    if let Some(token_accounts) = accounts.token_accounts {
+        metas.push(AccountMeta::new(*token_accounts.player_token.key, false));
+        metas.push(AccountMeta::new(*token_accounts.vault_token.key, false));
+        metas.push(AccountMeta::new_readonly(*token_accounts.token_program.key, false));
+        account_infos.push(token_accounts.player_token);
+        account_infos.push(token_accounts.vault_token);
+        account_infos.push(token_accounts.token_program);
+    }
+    let instruction = Instruction::new_with_borsh(
+        *accounts.race_program.key,
+        &RaceInstruction::CpiJoinRace(JoinRaceArgs { slot }),
+        metas,
+    );
+    account_infos.push(accounts.race_program);
+    invoke_signed(&instruction, &account_infos, signer_seeds)
+}
+
+/// Invokes CpiLeaveRace, `signer_seeds` must sign for the CPI authority and the player
+pub fn leave_race(accounts: LeaveRace, signer_seeds: &[&[&[u8]]]) -> ProgramResult {
+    let mut account_infos = vec![
+        accounts.caller_program,
+        accounts.cpi_authority,
+        accounts.config,
+        accounts.race,
+        accounts.player,
+        accounts.entry,
+        accounts.vault,
+        accounts.clock,
+    ];
+    let mut metas = vec![
+        AccountMeta::new_readonly(*account_infos[0].key, false),
+        AccountMeta::new_readonly(*account_infos[1].key, true),
+        AccountMeta::new_readonly(*account_infos[2].key, false),
+        AccountMeta::new(*account_infos[3].key, false),
+        AccountMeta::new(*account_infos[4].key, true),
+        AccountMeta::new(*account_infos[5].key, false),
+        AccountMeta::new(*account_infos[6].key, false),
+        AccountMeta::new_readonly(*account_infos[7].key, false),
+    ];
+    if let Some(token_accounts) = accounts.token_accounts {
+        // LeaveRace reads the vault side first and pays out to the player last
+        metas.push(AccountMeta::new(*token_accounts.vault_token.key, false));
+        metas.push(AccountMeta::new_readonly(*token_accounts.token_program.key, false));
+        metas.push(AccountMeta::new(*token_accounts.player_token.key, false));
+        account_infos.push(token_accounts.vault_token);
+        account_infos.push(token_accounts.token_program);
+        account_infos.push(token_accounts.player_token);
+    }
+    let instruction = Instruction::new_with_borsh(
+        *accounts.race_program.key,
+        &RaceInstruction::CpiLeaveRace,
+        metas,
+    );
+    account_infos.push(accounts.race_program);
+    invoke_signed(&instruction, &account_infos, signer_seeds)
+}
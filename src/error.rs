@@ -0,0 +1,259 @@
+//! Error types
+
+use num_derive::FromPrimitive;
+use solana_program::{
+    decode_error::DecodeError,
+    msg,
+    program_error::{PrintProgramError, ProgramError},
+};
+use thiserror::Error;
+
+/// Errors that may be returned by the Race program
+#[derive(Clone, Debug, Eq, Error, FromPrimitive, PartialEq)]
+pub enum RaceError {
+    /// Player Already exists!
+    #[error("Player Already exists!")]
+    PlayerFoundError,
+
+    /// Slot not available!
+    #[error("Slot not available!")]
+    SlotNotAvailableError,
+
+    /// Race already initialized!
+    #[error("Race already initialized!")]
+    AlreadyInitialized,
+
+    /// Race account does not match the derived race address
+    #[error("Race account does not match the derived race address")]
+    InvalidRaceKey,
+
+    /// Name too long
+    #[error("Name too long")]
+    NameTooLong,
+
+    /// Location too long
+    #[error("Location too long")]
+    LocationTooLong,
+
+    /// Game url too long
+    #[error("Game url too long")]
+    GameUrlTooLong,
+
+    /// Authority does not match the race authority
+    #[error("Authority does not match the race authority")]
+    AuthorityIncorrect,
+
+    /// Race authority is not a signer
+    #[error("Race authority is not a signer")]
+    AuthorityIsNotSigner,
+
+    /// Player is not a signer
+    #[error("Player is not a signer")]
+    PlayerIsNotSigner,
+
+    /// Vault account does not match the derived vault address
+    #[error("Vault account does not match the derived vault address")]
+    InvalidVaultKey,
+
+    /// Invalid system program
+    #[error("Invalid system program")]
+    InvalidSystemProgram,
+
+    /// NumericalOverflowError
+    #[error("NumericalOverflowError")]
+    NumericalOverflowError,
+
+    /// Vault token account is not the vault's associated token account for the fee mint
+    #[error("Vault token account is not the vault's associated token account for the fee mint")]
+    InvalidVaultTokenAccount,
+
+    /// Invalid token program
+    #[error("Invalid token program")]
+    InvalidTokenProgram,
+
+    /// Payout table must have at most MAX_PAYOUT_PLACES entries adding up to 10000 basis points
+    #[error("Payout table must have at most MAX_PAYOUT_PLACES entries adding up to 10000 basis points")]
+    InvalidPayoutTable,
+
+    /// Signer is neither the race authority nor its oracle
+    #[error("Signer is neither the race authority nor its oracle")]
+    SettlerIncorrect,
+
+    /// Results must list every player's slot exactly once
+    #[error("Results must list every player's slot exactly once")]
+    InvalidResults,
+
+    /// Race already settled
+    #[error("Race already settled")]
+    RaceAlreadySettled,
+
+    /// Payout account does not belong to the winning player
+    #[error("Payout account does not belong to the winning player")]
+    InvalidPayoutAccount,
+
+    /// Race status cannot move to the requested status
+    #[error("Race status cannot move to the requested status")]
+    InvalidStatusTransition,
+
+    /// Race is not open for registration
+    #[error("Race is not open for registration")]
+    RaceNotOpen,
+
+    /// Race has already started
+    #[error("Race has already started")]
+    RaceAlreadyStarted,
+
+    /// Race is settled or cancelled and can no longer change
+    #[error("Race is settled or cancelled and can no longer change")]
+    RaceClosed,
+
+    /// Race is not cancelled
+    #[error("Race is not cancelled")]
+    RaceNotCancelled,
+
+    /// Player not found in the race
+    #[error("Player not found in the race")]
+    PlayerNotFound,
+
+    /// Refund already claimed
+    #[error("Refund already claimed")]
+    RefundAlreadyClaimed,
+
+    /// Withdrawal fee cannot exceed 10000 basis points
+    #[error("Withdrawal fee cannot exceed 10000 basis points")]
+    InvalidWithdrawalFee,
+
+    /// Race is locked, players can no longer leave
+    #[error("Race is locked, players can no longer leave")]
+    RaceLocked,
+
+    /// Registration for the race is closed
+    #[error("Registration for the race is closed")]
+    RegistrationClosed,
+
+    /// Game has already ended
+    #[error("Game has already ended")]
+    GameEnded,
+
+    /// Race has not finished yet
+    #[error("Race has not finished yet")]
+    RaceNotFinished,
+
+    /// Race is full!
+    #[error("Race is full!")]
+    RaceFullError,
+
+    /// Slot out of range!
+    #[error("Slot out of range!")]
+    SlotOutOfRangeError,
+
+    /// Race cannot have more than MAX_PLAYERS players
+    #[error("Race cannot have more than MAX_PLAYERS players")]
+    TooManyPlayers,
+
+    /// Data type mismatch
+    #[error("Data type mismatch")]
+    DataTypeMismatch,
+
+    /// Race account uses an old layout, run MigrateRace first
+    #[error("Race account uses an old layout, run MigrateRace first")]
+    RaceNeedsMigration,
+
+    /// Race account is already on the current layout
+    #[error("Race account is already on the current layout")]
+    RaceAlreadyMigrated,
+
+    /// Race entry key is not derived from the race and player
+    #[error("Race entry key is not derived from the race and player")]
+    InvalidRaceEntryKey,
+
+    /// Config key is not derived from the program
+    #[error("Config key is not derived from the program")]
+    InvalidConfigKey,
+
+    /// Admin is not the config admin or did not sign
+    #[error("Admin is not the config admin or did not sign")]
+    AdminIncorrect,
+
+    /// Too many allowed programs
+    #[error("Too many allowed programs")]
+    TooManyAllowedPrograms,
+
+    /// Invoking program is not allowed to enter races
+    #[error("Invoking program is not allowed to enter races")]
+    CallerNotAllowed,
+
+    /// CPI authority is not derived from the invoking program or did not sign
+    #[error("CPI authority is not derived from the invoking program or did not sign")]
+    InvalidCpiAuthority,
+
+    /// Protocol fee cannot exceed 10000 basis points
+    #[error("Protocol fee cannot exceed 10000 basis points")]
+    InvalidProtocolFee,
+
+    /// Entry fee is above the config limit
+    #[error("Entry fee is above the config limit")]
+    EntryFeeTooHigh,
+
+    /// Program is paused
+    #[error("Program is paused")]
+    ProgramPaused,
+
+    /// Update does not change any field
+    #[error("Update does not change any field")]
+    EmptyUpdate,
+
+    /// Race terms cannot change once players joined
+    #[error("Race terms cannot change once players joined")]
+    TermsLocked,
+
+    /// Distance is outside MIN_DISTANCE and MAX_DISTANCE
+    #[error("Distance is outside MIN_DISTANCE and MAX_DISTANCE")]
+    InvalidDistance,
+
+    /// Minimum players cannot exceed max players
+    #[error("Minimum players cannot exceed max players")]
+    InvalidMinPlayers,
+
+    /// Race date has not been reached
+    #[error("Race date has not been reached")]
+    RaceNotStarted,
+
+    /// Race cannot advance yet
+    #[error("Race cannot advance yet")]
+    RaceNotReady,
+
+    /// Program config layout version is not supported by this program
+    #[error("Program config layout version is not supported by this program")]
+    UnsupportedConfigVersion,
+
+    /// Result is not signed by the result signer in the preceding Ed25519 instruction
+    #[error("Result is not signed by the result signer in the preceding Ed25519 instruction")]
+    InvalidResultAttestation,
+
+    /// Program data account is not the ProgramData account of the program
+    #[error("Program data account is not the ProgramData account of the program")]
+    InvalidProgramData,
+
+    /// Upgrade authority does not match the program or did not sign
+    #[error("Upgrade authority does not match the program or did not sign")]
+    UpgradeAuthorityIncorrect,
+}
+
+impl PrintProgramError for RaceError {
+    fn print<E>(&self) {
+        msg!(&self.to_string());
+    }
+}
+
+impl From<RaceError> for ProgramError {
+    fn from(e: RaceError) -> Self {
+        ProgramError::Custom(e as u32)
+    }
+}
+
+impl<T> DecodeError<T> for RaceError {
+    fn type_of() -> &'static str {
+        "Race Error"
+    }
+}
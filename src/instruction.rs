@@ -0,0 +1,832 @@
+//! Instruction types, and builders listing the accounts in the order the program reads them
+
+use crate::state::{
+    find_config_address, find_race_address, find_race_entry_address, find_vault_address,
+    RaceStatus,
+};
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{
+    bpf_loader_upgradeable,
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    system_program, sysvar,
+};
+use spl_associated_token_account::get_associated_token_address;
+
+/// Native Ed25519 signature verification program, not exported by this sdk version
+pub mod ed25519_program {
+    solana_program::declare_id!("Ed25519SigVerify111111111111111111111111111");
+}
+
+/// Size of the signature count and padding bytes of an Ed25519 instruction
+pub const ED25519_HEADER_LEN: usize = 2;
+
+/// Size of the offsets of one signature in an Ed25519 instruction
+pub const ED25519_OFFSETS_LEN: usize = 14;
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+/// Args for initialize call
+pub struct InitializeRaceArgs {
+    /// Id the race address is derived from
+    pub race_id: u64,
+    /// Number of slots, at most MAX_PLAYERS
+    pub max_players: u8,
+    /// Players needed for the race to start, at most `max_players`
+    pub min_players: u8,
+    /// Draft or Open
+    pub status: RaceStatus,
+    pub level: u8,
+    pub r#type: u8,
+    pub date: u64,
+    pub name: String,
+    pub location: String,
+    pub distance: u16,
+    pub entry_fee: u64,
+    pub fee_mint: Option<Pubkey>,
+    pub payout_table: Vec<u16>,
+    pub lock_period: u64,
+    pub withdrawal_fee_bps: u16,
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone, Default)]
+/// Args for update call, fields left as None are kept and at least one must be set
+pub struct UpdateRaceArgs {
+    pub status: Option<RaceStatus>,
+    pub name: Option<String>,
+    pub location: Option<String>,
+    // The terms below are locked once the first player joins
+    pub level: Option<u8>,
+    pub r#type: Option<u8>,
+    pub date: Option<u64>,
+    /// Between MIN_DISTANCE and MAX_DISTANCE
+    pub distance: Option<u16>,
+    pub entry_fee: Option<u64>,
+    pub payout_table: Option<Vec<u16>>,
+    pub lock_period: Option<u64>,
+    pub withdrawal_fee_bps: Option<u16>,
+    /// At most the race's max players
+    pub min_players: Option<u8>,
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+/// Args for create call
+pub struct UpdateGameArgs {
+    pub game_url: String,
+    pub end_date: u64,
+    pub oracle: Option<Pubkey>,
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+/// Args for join call
+pub struct JoinRaceArgs {
+    pub slot: u8,
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+/// Args for set authority call
+pub struct SetAuthorityArgs {
+    pub new_authority: Pubkey,
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+/// Args for sponsor call
+pub struct SponsorRaceArgs {
+    pub amount: u64,
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+/// Args for settle call
+pub struct SettleRaceArgs {
+    /// Slot of every player in finishing order, the first places are paid following
+    /// the payout table
+    pub results: Vec<u8>,
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+/// Args for initialize config call
+pub struct InitializeConfigArgs {
+    /// Share of the prize pool paid to the treasury, each race keeps the fee it was created with
+    pub protocol_fee_bps: u16,
+    pub treasury: Pubkey,
+    /// Highest entry fee a race may charge, 0 for no limit
+    pub max_entry_fee: u64,
+    /// Most slots a race may have, 0 for MAX_PLAYERS
+    pub max_players: u8,
+    /// Lamports AdvanceRace pays its caller
+    pub crank_reward: u64,
+    /// Game server key SubmitResult trusts
+    pub result_signer: Option<Pubkey>,
+    /// Programs allowed to enter races through CPI, at most MAX_ALLOWED_PROGRAMS
+    pub allowed_programs: Vec<Pubkey>,
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+/// Args for set allowed programs call
+pub struct SetAllowedProgramsArgs {
+    /// Replaces the current allowlist, at most MAX_ALLOWED_PROGRAMS
+    pub allowed_programs: Vec<Pubkey>,
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone, Default)]
+/// Args for update config call, fields left as None are kept
+pub struct UpdateConfigArgs {
+    pub admin: Option<Pubkey>,
+    pub protocol_fee_bps: Option<u16>,
+    pub treasury: Option<Pubkey>,
+    pub paused: Option<bool>,
+    pub max_entry_fee: Option<u64>,
+    pub max_players: Option<u8>,
+    pub crank_reward: Option<u64>,
+    pub result_signer: Option<Pubkey>,
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+/// Args for submit result call, signed by the result signer, see `result_message`
+pub struct SubmitResultArgs {
+    /// Hash of the full game result published at the race's game url
+    pub result_hash: [u8; 32],
+    /// Slot of every player in finishing order, as in SettleRaceArgs
+    pub results: Vec<u8>,
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+/// Args for set paused call
+pub struct SetPausedArgs {
+    pub paused: bool,
+}
+
+/// Instructions supported by the Race program.
+#[derive(BorshSerialize, BorshDeserialize, Clone)]
+pub enum RaceInstruction {
+    /// Update the given race details, once a player joined only the status, name and location
+    /// can change
+    ///   0. `[]` Program config
+    ///   1. `[writable]` Race account
+    ///   2. `[signer]` Race authority
+    UpdateRace(UpdateRaceArgs),
+    /// Update the game details, until the game ends. The oracle is locked once a player joined.
+    ///   0. `[]` Program config
+    ///   1. `[writable]` Race account
+    ///   2. `[signer]` Race authority
+    ///   3. `[]` Clock sysvar
+    UpdateGame(UpdateGameArgs),
+    /// Take a slot in the race before registration closes, paying the entry fee into the vault
+    ///   0. `[]` Program config
+    ///   1. `[writable]` Race account
+    ///   2. `[writable, signer]` Player
+    ///   3. `[writable]` Race entry, PDA of [PREFIX, program id, race account, player]
+    ///   4. `[writable]` Race vault
+    ///   5. `[]` System program
+    ///   6. `[]` Clock sysvar
+    ///   7. `[]` Rent sysvar
+    ///   8. `[writable]` Player token account, only when the race has a fee mint
+    ///   9. `[writable]` Vault associated token account for the fee mint, only when the race has a fee mint
+    ///   10. `[]` Token program, only when the race has a fee mint
+    ///   11. `[]` Instructions sysvar, always the last account. JoinRace must be a top-level
+    ///       instruction, invoking programs use CpiJoinRace
+    JoinRace(JoinRaceArgs),
+    /// Create the race account at the address derived from the race id, and its vault
+    ///   0. `[]` Program config
+    ///   1. `[writable]` Race account, PDA of [PREFIX, program id, race id]
+    ///   2. `[writable]` Race vault, PDA of [PREFIX, program id, race account, VAULT]
+    ///   3. `[writable, signer]` Payer
+    ///   4. `[]` Race authority
+    ///   5. `[]` System program
+    ///   6. `[]` Rent sysvar
+    InitializeRace(InitializeRaceArgs),
+    /// Hand the race over to a new authority
+    ///   0. `[]` Program config
+    ///   1. `[writable]` Race account
+    ///   2. `[signer]` Current race authority
+    SetAuthority(SetAuthorityArgs),
+    /// Add funds to the prize pool
+    ///   0. `[]` Program config
+    ///   1. `[writable]` Race account
+    ///   2. `[writable, signer]` Sponsor
+    ///   3. `[writable]` Race vault
+    ///   4. `[]` System program
+    ///   5. `[writable]` Sponsor token account, only when the race has a fee mint
+    ///   6. `[writable]` Vault associated token account for the fee mint, only when the race has a fee mint
+    ///   7. `[]` Token program, only when the race has a fee mint
+    SponsorRace(SponsorRaceArgs),
+    /// Record the results once the game ended and pay the prize pool out following the payout table,
+    /// a race without players pays its whole prize pool to the treasury
+    ///   0. `[]` Program config
+    ///   1. `[writable]` Race account
+    ///   2. `[signer]` Race authority or oracle
+    ///   3. `[writable]` Race vault
+    ///   4. `[]` Clock sysvar
+    ///   5. `[writable]` Vault associated token account for the fee mint, only when the race has a fee mint
+    ///   6. `[]` Token program, only when the race has a fee mint
+    ///   7. `[writable]` Treasury payout account, the treasury wallet or, when the race has a fee mint,
+    ///      the treasury's associated token account
+    ///   8. For each paid place, in finishing order:
+    ///      `[]` Race entry of the player,
+    ///      `[writable]` Payout account, the player wallet or, when the race has a fee mint,
+    ///      the player's token account
+    SettleRace(SettleRaceArgs),
+    /// Cancel the race, letting every player claim their entry fee back
+    ///   0. `[]` Program config
+    ///   1. `[writable]` Race account
+    ///   2. `[signer]` Race authority
+    CancelRace,
+    /// Return the entry fee of a cancelled race to the player
+    ///   0. `[]` Program config
+    ///   1. `[writable]` Race account
+    ///   2. `[writable, signer]` Player
+    ///   3. `[writable]` Race entry of the player
+    ///   4. `[writable]` Race vault
+    ///   5. `[writable]` Vault associated token account for the fee mint, only when the race has a fee mint
+    ///   6. `[]` Token program, only when the race has a fee mint
+    ///   7. `[writable]` Player token account, only when the race has a fee mint
+    ClaimRefund,
+    /// Give up the slot before the race locks, getting the entry fee back minus the withdrawal fee,
+    /// the race entry is closed and its rent returned to the player
+    ///   0. `[]` Program config
+    ///   1. `[writable]` Race account
+    ///   2. `[writable, signer]` Player
+    ///   3. `[writable]` Race entry of the player
+    ///   4. `[writable]` Race vault
+    ///   5. `[]` Clock sysvar
+    ///   6. `[writable]` Vault associated token account for the fee mint, only when the race has a fee mint
+    ///   7. `[]` Token program, only when the race has a fee mint
+    ///   8. `[writable]` Player token account, only when the race has a fee mint
+    ///   9. `[]` Instructions sysvar, always the last account. LeaveRace must be a top-level
+    ///      instruction, invoking programs use CpiLeaveRace
+    LeaveRace,
+    /// Upgrade a race account written with an older layout to the current one,
+    /// topping its balance up to rent exemption and moving the players to race entries
+    ///   0. `[]` Program config
+    ///   1. `[writable]` Race account
+    ///   2. `[writable, signer]` Payer
+    ///   3. `[]` System program
+    ///   4. `[]` Rent sysvar
+    ///   5. `[writable]` One race entry per taken slot of the old player table, in slot order
+    MigrateRace,
+    /// Create the program config, the signing admin manages it from then on. Only the upgrade
+    /// authority of the program can create it.
+    ///   0. `[writable]` Program config, PDA of [PREFIX, program id, CONFIG]
+    ///   1. `[writable, signer]` Payer
+    ///   2. `[signer]` Admin
+    ///   3. `[]` System program
+    ///   4. `[]` Rent sysvar
+    ///   5. `[]` ProgramData account of the program, PDA of [program id] under the upgradeable loader
+    ///   6. `[signer]` Upgrade authority of the program
+    InitializeConfig(InitializeConfigArgs),
+    /// Replace the programs allowed to enter races through CPI
+    ///   0. `[writable]` Program config
+    ///   1. `[signer]` Admin
+    SetAllowedPrograms(SetAllowedProgramsArgs),
+    /// JoinRace invoked by an allowed program, see the `cpi` module
+    ///   0. `[]` Invoking program
+    ///   1. `[signer]` CPI authority, PDA of [CPI_AUTHORITY] under the invoking program
+    ///   2. The JoinRace accounts without the instructions sysvar, the player may be a PDA
+    ///      signed for by the invoking program
+    CpiJoinRace(JoinRaceArgs),
+    /// LeaveRace invoked by an allowed program, see the `cpi` module
+    ///   0. `[]` Invoking program
+    ///   1. `[signer]` CPI authority, PDA of [CPI_AUTHORITY] under the invoking program
+    ///   2. The LeaveRace accounts without the instructions sysvar, the player may be a PDA
+    ///      signed for by the invoking program
+    CpiLeaveRace,
+    /// Change the program config
+    ///   0. `[writable]` Program config
+    ///   1. `[signer]` Admin
+    UpdateConfig(UpdateConfigArgs),
+    /// Pause or resume the program. While paused only the config instructions and the paths
+    /// getting players their entry fees back, CancelRace, ClaimRefund and LeaveRace, are
+    /// processed. CheckRaceStart still cancels races short of players but starts none.
+    ///   0. `[writable]` Program config
+    ///   1. `[signer]` Admin
+    SetPaused(SetPausedArgs),
+    /// Start an open, full or closed race once its date is reached, or cancel it when fewer than
+    /// its minimum players joined so they can claim their entry fees back. Anyone can call it,
+    /// while the program is paused it only cancels.
+    ///   0. `[]` Program config
+    ///   1. `[writable]` Race account
+    ///   2. `[]` Clock sysvar
+    CheckRaceStart,
+    /// Move the race as far as the clock allows through Open or Full -> Closed at the lock time,
+    /// Closed -> Running at the race date, cancelling it when fewer than its minimum players
+    /// joined, and Running -> AwaitingResult at the end date. Anyone can call it, the caller
+    /// gets the config crank reward out of the lamports the vault holds beyond rent exemption
+    /// and the prize pool, race creators fund it by transferring lamports to the vault.
+    ///   0. `[]` Program config
+    ///   1. `[writable]` Race account
+    ///   2. `[writable]` Race vault
+    ///   3. `[writable, signer]` Caller
+    ///   4. `[]` Clock sysvar
+    ///   5. `[]` Rent sysvar
+    AdvanceRace,
+    /// SettleRace on a result signed by the config result signer. The instruction right before
+    /// it in the transaction must be an Ed25519 program instruction verifying one signature of
+    /// the result signer over `result_message`, with its data inside that instruction.
+    ///   0. `[]` Program config
+    ///   1. `[writable]` Race account
+    ///   2. `[writable]` Race vault
+    ///   3. `[]` Clock sysvar
+    ///   4. `[]` Instructions sysvar
+    ///   5. The SettleRace accounts from the vault token accounts on
+    SubmitResult(SubmitResultArgs),
+}
+
+impl RaceInstruction {
+    /// Whether the instruction is rejected while the program is paused
+    pub fn is_paused_by_config(&self) -> bool {
+        !matches!(
+            self,
+            RaceInstruction::CancelRace
+                | RaceInstruction::ClaimRefund
+                | RaceInstruction::LeaveRace
+                | RaceInstruction::CpiLeaveRace
+                | RaceInstruction::CheckRaceStart
+                | RaceInstruction::InitializeConfig(_)
+                | RaceInstruction::SetAllowedPrograms(_)
+                | RaceInstruction::UpdateConfig(_)
+                | RaceInstruction::SetPaused(_)
+        )
+    }
+}
+
+/// Creates an InitializeRace instruction, the race address is derived from `args.race_id`
+pub fn initialize_race(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    authority: &Pubkey,
+    args: InitializeRaceArgs,
+) -> Instruction {
+    let (race, _) = find_race_address(program_id, args.race_id);
+    let (vault, _) = find_vault_address(program_id, &race);
+    Instruction::new_with_borsh(
+        *program_id,
+        &RaceInstruction::InitializeRace(args),
+        vec![
+            config_account(program_id),
+            AccountMeta::new(race, false),
+            AccountMeta::new(vault, false),
+            AccountMeta::new(*payer, true),
+            AccountMeta::new_readonly(*authority, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+    )
+}
+
+/// Creates an UpdateRace instruction
+pub fn update_race(
+    program_id: &Pubkey,
+    race: &Pubkey,
+    authority: &Pubkey,
+    args: UpdateRaceArgs,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &RaceInstruction::UpdateRace(args),
+        vec![
+            config_account(program_id),
+            AccountMeta::new(*race, false),
+            AccountMeta::new_readonly(*authority, true),
+        ],
+    )
+}
+
+/// Creates an UpdateGame instruction
+pub fn update_game(
+    program_id: &Pubkey,
+    race: &Pubkey,
+    authority: &Pubkey,
+    args: UpdateGameArgs,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &RaceInstruction::UpdateGame(args),
+        vec![
+            config_account(program_id),
+            AccountMeta::new(*race, false),
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+        ],
+    )
+}
+
+/// Creates a JoinRace instruction. For fee mint races the entry fee is paid from
+/// the player's associated token account.
+pub fn join_race(
+    program_id: &Pubkey,
+    race: &Pubkey,
+    player: &Pubkey,
+    fee_mint: Option<&Pubkey>,
+    slot: u8,
+) -> Instruction {
+    let (entry, _) = find_race_entry_address(program_id, race, player);
+    let (vault, _) = find_vault_address(program_id, race);
+    let mut accounts = vec![
+        config_account(program_id),
+        AccountMeta::new(*race, false),
+        AccountMeta::new(*player, true),
+        AccountMeta::new(entry, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+    ];
+    if let Some(fee_mint) = fee_mint {
+        accounts.extend(deposit_token_accounts(player, &vault, fee_mint));
+    }
+    accounts.push(AccountMeta::new_readonly(sysvar::instructions::id(), false));
+    Instruction::new_with_borsh(
+        *program_id,
+        &RaceInstruction::JoinRace(JoinRaceArgs { slot }),
+        accounts,
+    )
+}
+
+/// Creates a SetAuthority instruction
+pub fn set_authority(
+    program_id: &Pubkey,
+    race: &Pubkey,
+    authority: &Pubkey,
+    new_authority: &Pubkey,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &RaceInstruction::SetAuthority(SetAuthorityArgs {
+            new_authority: *new_authority,
+        }),
+        vec![
+            config_account(program_id),
+            AccountMeta::new(*race, false),
+            AccountMeta::new_readonly(*authority, true),
+        ],
+    )
+}
+
+/// Creates a SponsorRace instruction. For fee mint races the amount is paid from
+/// the sponsor's associated token account.
+pub fn sponsor_race(
+    program_id: &Pubkey,
+    race: &Pubkey,
+    sponsor: &Pubkey,
+    fee_mint: Option<&Pubkey>,
+    amount: u64,
+) -> Instruction {
+    let (vault, _) = find_vault_address(program_id, race);
+    let mut accounts = vec![
+        config_account(program_id),
+        AccountMeta::new(*race, false),
+        AccountMeta::new(*sponsor, true),
+        AccountMeta::new(vault, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    if let Some(fee_mint) = fee_mint {
+        accounts.extend(deposit_token_accounts(sponsor, &vault, fee_mint));
+    }
+    Instruction::new_with_borsh(
+        *program_id,
+        &RaceInstruction::SponsorRace(SponsorRaceArgs { amount }),
+        accounts,
+    )
+}
+
+/// Creates a SettleRace instruction. `winners` are the players of the paid places in
+/// finishing order and `treasury` the config treasury, for fee mint races they are paid
+/// to their associated token accounts.
+pub fn settle_race(
+    program_id: &Pubkey,
+    race: &Pubkey,
+    settler: &Pubkey,
+    treasury: &Pubkey,
+    fee_mint: Option<&Pubkey>,
+    results: Vec<u8>,
+    winners: &[Pubkey],
+) -> Instruction {
+    let (vault, _) = find_vault_address(program_id, race);
+    let mut accounts = vec![
+        config_account(program_id),
+        AccountMeta::new(*race, false),
+        AccountMeta::new_readonly(*settler, true),
+        AccountMeta::new(vault, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    if let Some(fee_mint) = fee_mint {
+        accounts.extend(withdraw_token_accounts(&vault, fee_mint));
+    }
+    accounts.push(AccountMeta::new(payout_address(treasury, fee_mint), false));
+    for winner in winners {
+        let (entry, _) = find_race_entry_address(program_id, race, winner);
+        accounts.push(AccountMeta::new_readonly(entry, false));
+        accounts.push(AccountMeta::new(payout_address(winner, fee_mint), false));
+    }
+    Instruction::new_with_borsh(
+        *program_id,
+        &RaceInstruction::SettleRace(SettleRaceArgs { results }),
+        accounts,
+    )
+}
+
+/// Creates a CancelRace instruction
+pub fn cancel_race(program_id: &Pubkey, race: &Pubkey, authority: &Pubkey) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &RaceInstruction::CancelRace,
+        vec![
+            config_account(program_id),
+            AccountMeta::new(*race, false),
+            AccountMeta::new_readonly(*authority, true),
+        ],
+    )
+}
+
+/// Creates a ClaimRefund instruction. For fee mint races the refund goes to
+/// the player's associated token account.
+pub fn claim_refund(
+    program_id: &Pubkey,
+    race: &Pubkey,
+    player: &Pubkey,
+    fee_mint: Option<&Pubkey>,
+) -> Instruction {
+    let (entry, _) = find_race_entry_address(program_id, race, player);
+    let (vault, _) = find_vault_address(program_id, race);
+    let mut accounts = vec![
+        config_account(program_id),
+        AccountMeta::new(*race, false),
+        AccountMeta::new(*player, true),
+        AccountMeta::new(entry, false),
+        AccountMeta::new(vault, false),
+    ];
+    if let Some(fee_mint) = fee_mint {
+        accounts.extend(withdraw_token_accounts(&vault, fee_mint));
+        accounts.push(AccountMeta::new(payout_address(player, Some(fee_mint)), false));
+    }
+    Instruction::new_with_borsh(*program_id, &RaceInstruction::ClaimRefund, accounts)
+}
+
+/// Creates a LeaveRace instruction. For fee mint races the refund goes to
+/// the player's associated token account.
+pub fn leave_race(
+    program_id: &Pubkey,
+    race: &Pubkey,
+    player: &Pubkey,
+    fee_mint: Option<&Pubkey>,
+) -> Instruction {
+    let (entry, _) = find_race_entry_address(program_id, race, player);
+    let (vault, _) = find_vault_address(program_id, race);
+    let mut accounts = vec![
+        config_account(program_id),
+        AccountMeta::new(*race, false),
+        AccountMeta::new(*player, true),
+        AccountMeta::new(entry, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    if let Some(fee_mint) = fee_mint {
+        accounts.extend(withdraw_token_accounts(&vault, fee_mint));
+        accounts.push(AccountMeta::new(payout_address(player, Some(fee_mint)), false));
+    }
+    accounts.push(AccountMeta::new_readonly(sysvar::instructions::id(), false));
+    Instruction::new_with_borsh(*program_id, &RaceInstruction::LeaveRace, accounts)
+}
+
+/// Creates a MigrateRace instruction. `players` are the players of the old player table
+/// in slot order, each of them gets a race entry.
+pub fn migrate_race(
+    program_id: &Pubkey,
+    race: &Pubkey,
+    payer: &Pubkey,
+    players: &[Pubkey],
+) -> Instruction {
+    let mut accounts = vec![
+        config_account(program_id),
+        AccountMeta::new(*race, false),
+        AccountMeta::new(*payer, true),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+    ];
+    for player in players {
+        let (entry, _) = find_race_entry_address(program_id, race, player);
+        accounts.push(AccountMeta::new(entry, false));
+    }
+    Instruction::new_with_borsh(*program_id, &RaceInstruction::MigrateRace, accounts)
+}
+
+/// Creates an InitializeConfig instruction
+pub fn initialize_config(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    admin: &Pubkey,
+    upgrade_authority: &Pubkey,
+    args: InitializeConfigArgs,
+) -> Instruction {
+    let (config, _) = find_config_address(program_id);
+    let (program_data, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    Instruction::new_with_borsh(
+        *program_id,
+        &RaceInstruction::InitializeConfig(args),
+        vec![
+            AccountMeta::new(config, false),
+            AccountMeta::new(*payer, true),
+            AccountMeta::new_readonly(*admin, true),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(program_data, false),
+            AccountMeta::new_readonly(*upgrade_authority, true),
+        ],
+    )
+}
+
+/// Creates a SetAllowedPrograms instruction
+pub fn set_allowed_programs(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    allowed_programs: Vec<Pubkey>,
+) -> Instruction {
+    let (config, _) = find_config_address(program_id);
+    Instruction::new_with_borsh(
+        *program_id,
+        &RaceInstruction::SetAllowedPrograms(SetAllowedProgramsArgs { allowed_programs }),
+        vec![
+            AccountMeta::new(config, false),
+            AccountMeta::new_readonly(*admin, true),
+        ],
+    )
+}
+
+/// Creates an UpdateConfig instruction
+pub fn update_config(program_id: &Pubkey, admin: &Pubkey, args: UpdateConfigArgs) -> Instruction {
+    let (config, _) = find_config_address(program_id);
+    Instruction::new_with_borsh(
+        *program_id,
+        &RaceInstruction::UpdateConfig(args),
+        vec![
+            AccountMeta::new(config, false),
+            AccountMeta::new_readonly(*admin, true),
+        ],
+    )
+}
+
+/// Creates a SetPaused instruction
+pub fn set_paused(program_id: &Pubkey, admin: &Pubkey, paused: bool) -> Instruction {
+    let (config, _) = find_config_address(program_id);
+    Instruction::new_with_borsh(
+        *program_id,
+        &RaceInstruction::SetPaused(SetPausedArgs { paused }),
+        vec![
+            AccountMeta::new(config, false),
+            AccountMeta::new_readonly(*admin, true),
+        ],
+    )
+}
+
+/// Creates a CheckRaceStart instruction
+pub fn check_race_start(program_id: &Pubkey, race: &Pubkey) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &RaceInstruction::CheckRaceStart,
+        vec![
+            config_account(program_id),
+            AccountMeta::new(*race, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+        ],
+    )
+}
+
+/// Creates an AdvanceRace instruction
+pub fn advance_race(program_id: &Pubkey, race: &Pubkey, caller: &Pubkey) -> Instruction {
+    let (vault, _) = find_vault_address(program_id, race);
+    Instruction::new_with_borsh(
+        *program_id,
+        &RaceInstruction::AdvanceRace,
+        vec![
+            config_account(program_id),
+            AccountMeta::new(*race, false),
+            AccountMeta::new(vault, false),
+            AccountMeta::new(*caller, true),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+    )
+}
+
+/// Message the result signer signs for SubmitResult: the race address, the result hash
+/// and the slot of every player in finishing order
+pub fn result_message(race: &Pubkey, result_hash: &[u8; 32], results: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(64 + results.len());
+    message.extend_from_slice(race.as_ref());
+    message.extend_from_slice(result_hash);
+    message.extend_from_slice(results);
+    message
+}
+
+/// Creates the Ed25519 program instruction verifying `signature` of `signer` over `message`,
+/// the public key, signature and message are all stored in the instruction itself
+pub fn verify_ed25519_signature(
+    signer: &Pubkey,
+    signature: &[u8; 64],
+    message: &[u8],
+) -> Instruction {
+    let public_key_offset = ED25519_HEADER_LEN + ED25519_OFFSETS_LEN;
+    let signature_offset = public_key_offset + 32;
+    let message_offset = signature_offset + 64;
+    let mut data = vec![1, 0];
+    for value in [
+        signature_offset,
+        u16::MAX as usize,
+        public_key_offset,
+        u16::MAX as usize,
+        message_offset,
+        message.len(),
+        u16::MAX as usize,
+    ] {
+        data.extend_from_slice(&(value as u16).to_le_bytes());
+    }
+    data.extend_from_slice(signer.as_ref());
+    data.extend_from_slice(signature);
+    data.extend_from_slice(message);
+    Instruction {
+        program_id: ed25519_program::id(),
+        accounts: vec![],
+        data,
+    }
+}
+
+/// Creates a SubmitResult instruction, to follow the `verify_ed25519_signature` instruction
+/// of the result signer's signature over `result_message`. Accounts as in `settle_race`.
+#[allow(clippy::too_many_arguments)]
+pub fn submit_result(
+    program_id: &Pubkey,
+    race: &Pubkey,
+    treasury: &Pubkey,
+    fee_mint: Option<&Pubkey>,
+    result_hash: [u8; 32],
+    results: Vec<u8>,
+    winners: &[Pubkey],
+) -> Instruction {
+    let (vault, _) = find_vault_address(program_id, race);
+    let mut accounts = vec![
+        config_account(program_id),
+        AccountMeta::new(*race, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(sysvar::instructions::id(), false),
+    ];
+    if let Some(fee_mint) = fee_mint {
+        accounts.extend(withdraw_token_accounts(&vault, fee_mint));
+    }
+    accounts.push(AccountMeta::new(payout_address(treasury, fee_mint), false));
+    for winner in winners {
+        let (entry, _) = find_race_entry_address(program_id, race, winner);
+        accounts.push(AccountMeta::new_readonly(entry, false));
+        accounts.push(AccountMeta::new(payout_address(winner, fee_mint), false));
+    }
+    Instruction::new_with_borsh(
+        *program_id,
+        &RaceInstruction::SubmitResult(SubmitResultArgs {
+            result_hash,
+            results,
+        }),
+        accounts,
+    )
+}
+
+/// Program config, read by every race instruction
+fn config_account(program_id: &Pubkey) -> AccountMeta {
+    AccountMeta::new_readonly(find_config_address(program_id).0, false)
+}
+
+/// Token accounts paying into the vault: source, vault token account and token program
+fn deposit_token_accounts(owner: &Pubkey, vault: &Pubkey, fee_mint: &Pubkey) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(get_associated_token_address(owner, fee_mint), false),
+        AccountMeta::new(get_associated_token_address(vault, fee_mint), false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ]
+}
+
+/// Token accounts paying out of the vault: vault token account and token program
+fn withdraw_token_accounts(vault: &Pubkey, fee_mint: &Pubkey) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(get_associated_token_address(vault, fee_mint), false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ]
+}
+
+/// Account `recipient` is paid to, their wallet or their associated token account
+fn payout_address(recipient: &Pubkey, fee_mint: Option<&Pubkey>) -> Pubkey {
+    match fee_mint {
+        Some(fee_mint) => get_associated_token_address(recipient, fee_mint),
+        None => *recipient,
+    }
+}
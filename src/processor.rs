@@ -0,0 +1,2959 @@
+//! Program state processor
+
+use crate::{
+    error::RaceError,
+    instruction::{
+        result_message, InitializeConfigArgs, InitializeRaceArgs, JoinRaceArgs, RaceInstruction,
+        SetAllowedProgramsArgs, SetAuthorityArgs, SetPausedArgs, SettleRaceArgs, SponsorRaceArgs,
+        SubmitResultArgs, UpdateConfigArgs, UpdateGameArgs, UpdateRaceArgs,
+    },
+    state::{
+        find_config_address, find_cpi_authority_address, find_race_address, Key, ProgramConfig,
+        RaceAccount, RaceEntry, RaceStatus, CONFIG, LEGACY_MAX_PLAYERS, MAX_PLAYERS, PAYOUT_BPS,
+        PREFIX, PROGRAM_CONFIG_LEN, PROGRAM_CONFIG_VERSION, RACE_ACCOUNT_LEN, RACE_ACCOUNT_VERSION,
+        SLOT_BITMAP_LEN, VAULT,
+    },
+    utils::{
+        assert_result_attestation, assert_upgrade_authority, assert_valid_distance, assert_valid_withdrawal_fee, assert_vault,
+        close_race_entry, create_or_allocate_account_raw, create_race_entry, deposit_to_vault,
+        read_legacy_players, split_prize_pool, unix_timestamp, VaultAccounts,
+    },
+};
+use borsh::BorshDeserialize;
+use num_traits::FromPrimitive;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_instruction, system_program,
+    sysvar::{
+        self,
+        instructions::{load_current_index, load_instruction_at},
+        rent::Rent,
+        Sysvar,
+    },
+};
+use std::slice::Iter;
+
+/// Process a RaceInstruction, the accounts of each are listed on its variant
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    msg!("Race Rust program entrypoint");
+    let instruction = RaceInstruction::try_from_slice(instruction_data)?;
+    if instruction.is_paused_by_config() {
+        assert_not_paused(program_id, accounts, &instruction)?;
+    }
+    match instruction {
+        RaceInstruction::UpdateRace(args) => {
+            msg!("Instruction: UpdateRace");
+            if let Some(name) = &args.name {
+                msg!("Name: {}", name);
+            }
+            process_update_race(
+                program_id,
+                accounts,
+                args
+            )
+        }
+        RaceInstruction::UpdateGame(args) => {
+            msg!("Instruction: UpdateGame: {}", &args.game_url);
+            process_update_game(
+                program_id,
+                accounts,
+                args
+            )
+        }
+        RaceInstruction::JoinRace(args) => {
+            msg!("Instruction: JoinRace: {}", &args.slot);
+            let race_accounts = assert_top_level_caller(program_id, accounts)?;
+            process_join_race(
+                program_id,
+                race_accounts,
+                args
+            )
+        }
+        RaceInstruction::InitializeRace(args) => {
+            msg!("Instruction: InitializeRace: {}", &args.race_id);
+            process_initialize_race(
+                program_id,
+                accounts,
+                args
+            )
+        }
+        RaceInstruction::SetAuthority(args) => {
+            msg!("Instruction: SetAuthority: {}", &args.new_authority);
+            process_set_authority(
+                program_id,
+                accounts,
+                args
+            )
+        }
+        RaceInstruction::SponsorRace(args) => {
+            msg!("Instruction: SponsorRace: {}", &args.amount);
+            process_sponsor_race(
+                program_id,
+                accounts,
+                args
+            )
+        }
+        RaceInstruction::SettleRace(args) => {
+            msg!("Instruction: SettleRace");
+            process_settle_race(
+                program_id,
+                accounts,
+                args
+            )
+        }
+        RaceInstruction::CancelRace => {
+            msg!("Instruction: CancelRace");
+            process_cancel_race(
+                program_id,
+                accounts,
+            )
+        }
+        RaceInstruction::ClaimRefund => {
+            msg!("Instruction: ClaimRefund");
+            process_claim_refund(
+                program_id,
+                accounts,
+            )
+        }
+        RaceInstruction::LeaveRace => {
+            msg!("Instruction: LeaveRace");
+            let race_accounts = assert_top_level_caller(program_id, accounts)?;
+            process_leave_race(
+                program_id,
+                race_accounts,
+            )
+        }
+        RaceInstruction::MigrateRace => {
+            msg!("Instruction: MigrateRace");
+            process_migrate_race(
+                program_id,
+                accounts,
+            )
+        }
+        RaceInstruction::InitializeConfig(args) => {
+            msg!("Instruction: InitializeConfig");
+            process_initialize_config(
+                program_id,
+                accounts,
+                args
+            )
+        }
+        RaceInstruction::SetAllowedPrograms(args) => {
+            msg!("Instruction: SetAllowedPrograms");
+            process_set_allowed_programs(
+                program_id,
+                accounts,
+                args
+            )
+        }
+        RaceInstruction::CpiJoinRace(args) => {
+            msg!("Instruction: CpiJoinRace: {}", &args.slot);
+            let race_accounts = assert_cpi_caller(program_id, accounts)?;
+            process_join_race(
+                program_id,
+                race_accounts,
+                args
+            )
+        }
+        RaceInstruction::CpiLeaveRace => {
+            msg!("Instruction: CpiLeaveRace");
+            let race_accounts = assert_cpi_caller(program_id, accounts)?;
+            process_leave_race(
+                program_id,
+                race_accounts,
+            )
+        }
+        RaceInstruction::UpdateConfig(args) => {
+            msg!("Instruction: UpdateConfig");
+            process_update_config(
+                program_id,
+                accounts,
+                args
+            )
+        }
+        RaceInstruction::SetPaused(args) => {
+            msg!("Instruction: SetPaused: {}", &args.paused);
+            process_set_paused(
+                program_id,
+                accounts,
+                args
+            )
+        }
+        RaceInstruction::CheckRaceStart => {
+            msg!("Instruction: CheckRaceStart");
+            process_check_race_start(
+                program_id,
+                accounts,
+            )
+        }
+        RaceInstruction::AdvanceRace => {
+            msg!("Instruction: AdvanceRace");
+            process_advance_race(
+                program_id,
+                accounts,
+            )
+        }
+        RaceInstruction::SubmitResult(args) => {
+            msg!("Instruction: SubmitResult");
+            process_submit_result(
+                program_id,
+                accounts,
+                args
+            )
+        }
+    }
+}
+
+pub fn process_initialize_race(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: InitializeRaceArgs,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let race_account_info = next_account_info(accounts_iter)?;
+    let vault_info = next_account_info(accounts_iter)?;
+    let payer_info = next_account_info(accounts_iter)?;
+    let authority_info = next_account_info(accounts_iter)?;
+    let system_program_info = next_account_info(accounts_iter)?;
+    let rent_info = next_account_info(accounts_iter)?;
+
+    if !payer_info.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let race_id = args.race_id.to_le_bytes();
+    let (race_key, bump_seed) = find_race_address(program_id, args.race_id);
+    if race_key != *race_account_info.key {
+        return Err(RaceError::InvalidRaceKey.into());
+    }
+
+    if race_account_info.owner == program_id || !race_account_info.data_is_empty() {
+        return Err(RaceError::AlreadyInitialized.into());
+    }
+
+    assert_valid_withdrawal_fee(args.withdrawal_fee_bps)?;
+    assert_valid_distance(args.distance)?;
+    if args.max_players as usize > MAX_PLAYERS {
+        return Err(RaceError::TooManyPlayers.into());
+    }
+    if args.min_players > args.max_players {
+        return Err(RaceError::InvalidMinPlayers.into());
+    }
+    let protocol_fee_bps = {
+        let config = ProgramConfig::from_account_info(program_id, config_info)?;
+        config.assert_race_limits(args.entry_fee, args.max_players)?;
+        config.protocol_fee_bps
+    };
+    if !matches!(args.status, RaceStatus::Draft | RaceStatus::Open) {
+        return Err(RaceError::InvalidStatusTransition.into());
+    }
+
+    let race_signer_seeds = &[
+        PREFIX.as_bytes(),
+        program_id.as_ref(),
+        &race_id,
+        &[bump_seed],
+    ];
+    create_or_allocate_account_raw(
+        *program_id,
+        race_account_info,
+        rent_info,
+        system_program_info,
+        payer_info,
+        RACE_ACCOUNT_LEN,
+        race_signer_seeds,
+    )?;
+
+    let vault_bump_seed = assert_vault(program_id, race_account_info, vault_info)?;
+    let vault_signer_seeds = &[
+        PREFIX.as_bytes(),
+        program_id.as_ref(),
+        race_account_info.key.as_ref(),
+        VAULT.as_bytes(),
+        &[vault_bump_seed],
+    ];
+    create_or_allocate_account_raw(
+        *program_id,
+        vault_info,
+        rent_info,
+        system_program_info,
+        payer_info,
+        0,
+        vault_signer_seeds,
+    )?;
+
+    let mut race_account = RaceAccount::from_account_info_unchecked(race_account_info)?;
+    race_account.key = Key::RaceAccount as u8;
+    race_account.version = RACE_ACCOUNT_VERSION;
+    race_account.authority = authority_info.key.to_bytes();
+    race_account.protocol_fee_bps = protocol_fee_bps;
+    race_account.set_status(args.status);
+    race_account.level = args.level;
+    race_account.r#type = args.r#type;
+    race_account.date = args.date;
+    race_account.set_name(&args.name)?;
+    race_account.set_location(&args.location)?;
+    race_account.distance = args.distance;
+    race_account.entry_fee = args.entry_fee;
+    race_account.fee_mint = args.fee_mint.unwrap_or_default().to_bytes();
+    race_account.lock_period = args.lock_period;
+    race_account.withdrawal_fee_bps = args.withdrawal_fee_bps;
+    race_account.max_players = args.max_players;
+    race_account.min_players = args.min_players;
+    race_account.set_payout_table(&args.payout_table)?;
+    Ok(())
+}
+
+pub fn process_update_race(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: UpdateRaceArgs,
+) -> ProgramResult {
+    // Iterating accounts is safer than indexing
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+
+    // Race account to update
+    let account = next_account_info(accounts_iter)?;
+
+    // The account must be owned by the program in order to modify its data
+    if account.owner != program_id {
+        msg!("Race Account does not have the correct program id");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let authority_info = next_account_info(accounts_iter)?;
+
+    // Only the race authority may update the race
+    let mut race_account = RaceAccount::from_account_info(account)?;
+    race_account.assert_authority(authority_info)?;
+    if args == UpdateRaceArgs::default() {
+        return Err(RaceError::EmptyUpdate.into());
+    }
+    let status = race_account.status()?;
+    if status.is_closed() {
+        return Err(RaceError::RaceClosed.into());
+    }
+    msg!("Current Name: {}", race_account.name());
+
+    let changes_terms = args.level.is_some()
+        || args.r#type.is_some()
+        || args.date.is_some()
+        || args.distance.is_some()
+        || args.entry_fee.is_some()
+        || args.payout_table.is_some()
+        || args.lock_period.is_some()
+        || args.withdrawal_fee_bps.is_some()
+        || args.min_players.is_some();
+    if changes_terms && race_account.terms_locked() {
+        return Err(RaceError::TermsLocked.into());
+    }
+
+    if let Some(entry_fee) = args.entry_fee {
+        ProgramConfig::from_account_info(program_id, config_info)?
+            .assert_race_limits(entry_fee, race_account.max_players)?;
+        race_account.entry_fee = entry_fee;
+    }
+    if let Some(withdrawal_fee_bps) = args.withdrawal_fee_bps {
+        assert_valid_withdrawal_fee(withdrawal_fee_bps)?;
+        race_account.withdrawal_fee_bps = withdrawal_fee_bps;
+    }
+    if let Some(distance) = args.distance {
+        assert_valid_distance(distance)?;
+        race_account.distance = distance;
+    }
+    if let Some(payout_table) = &args.payout_table {
+        race_account.set_payout_table(payout_table)?;
+    }
+    if let Some(name) = &args.name {
+        race_account.set_name(name)?;
+    }
+    if let Some(location) = &args.location {
+        race_account.set_location(location)?;
+    }
+    if let Some(level) = args.level {
+        race_account.level = level;
+    }
+    if let Some(r#type) = args.r#type {
+        race_account.r#type = r#type;
+    }
+    if let Some(date) = args.date {
+        race_account.date = date;
+    }
+    if let Some(lock_period) = args.lock_period {
+        race_account.lock_period = lock_period;
+    }
+    if let Some(min_players) = args.min_players {
+        if min_players > race_account.max_players {
+            return Err(RaceError::InvalidMinPlayers.into());
+        }
+        race_account.min_players = min_players;
+    }
+    if let Some(new_status) = args.status {
+        if !status.can_transition_to(new_status) {
+            return Err(RaceError::InvalidStatusTransition.into());
+        }
+        if new_status == RaceStatus::Draft && race_account.terms_locked() {
+            return Err(RaceError::InvalidStatusTransition.into());
+        }
+        race_account.set_status(new_status);
+    }
+    Ok(())
+}
+
+pub fn process_update_game(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: UpdateGameArgs,
+) -> ProgramResult {
+    // Iterating accounts is safer than indexing
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+
+    // Race account to update
+    let account = next_account_info(accounts_iter)?;
+
+    // The account must be owned by the program in order to modify its data
+    if account.owner != program_id {
+        msg!("Race Account does not have the correct program id");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    ProgramConfig::from_account_info(program_id, config_info)?;
+
+    let authority_info = next_account_info(accounts_iter)?;
+    let clock_info = next_account_info(accounts_iter)?;
+
+    // Only the race authority may update the race
+    let mut race_account = RaceAccount::from_account_info(account)?;
+    race_account.assert_authority(authority_info)?;
+    let status = race_account.status()?;
+    if status.is_closed() {
+        return Err(RaceError::RaceClosed.into());
+    }
+    if !matches!(
+        status,
+        RaceStatus::Draft | RaceStatus::Open | RaceStatus::Full | RaceStatus::Closed
+    ) {
+        return Err(RaceError::RaceAlreadyStarted.into());
+    }
+    if race_account.end_date != 0 && unix_timestamp(clock_info)? >= race_account.end_date {
+        return Err(RaceError::GameEnded.into());
+    }
+    if args.oracle != race_account.oracle() && race_account.terms_locked() {
+        return Err(RaceError::TermsLocked.into());
+    }
+    race_account.set_game_url(&args.game_url)?;
+    race_account.end_date = args.end_date;
+    race_account.oracle = args.oracle.unwrap_or_default().to_bytes();
+    Ok(())
+}
+
+pub fn process_set_authority(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: SetAuthorityArgs,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let account = next_account_info(accounts_iter)?;
+    let authority_info = next_account_info(accounts_iter)?;
+
+    if account.owner != program_id {
+        msg!("Race Account does not have the correct program id");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    ProgramConfig::from_account_info(program_id, config_info)?;
+
+    let mut race_account = RaceAccount::from_account_info(account)?;
+    race_account.assert_authority(authority_info)?;
+    race_account.authority = args.new_authority.to_bytes();
+    Ok(())
+}
+
+pub fn process_join_race(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: JoinRaceArgs,
+) -> ProgramResult {
+    // Iterating accounts is safer than indexing
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let account = next_account_info(accounts_iter)?;
+    let player_info = next_account_info(accounts_iter)?;
+    let entry_info = next_account_info(accounts_iter)?;
+    let vault_info = next_account_info(accounts_iter)?;
+    let system_program_info = next_account_info(accounts_iter)?;
+    let clock_info = next_account_info(accounts_iter)?;
+    let rent_info = next_account_info(accounts_iter)?;
+
+    // The account must be owned by the program in order to modify its data
+    if account.owner != program_id {
+        msg!("Race Account does not have the correct program id");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    ProgramConfig::from_account_info(program_id, config_info)?;
+
+    if !player_info.is_signer {
+        return Err(RaceError::PlayerIsNotSigner.into());
+    }
+
+    let mut race_account = RaceAccount::from_account_info(account)?;
+    let status = race_account.status()?;
+    if status == RaceStatus::Full || race_account.player_count() >= race_account.max_players as usize {
+        return Err(RaceError::RaceFullError.into());
+    }
+    if status != RaceStatus::Open {
+        return Err(RaceError::RaceNotOpen.into());
+    }
+    if unix_timestamp(clock_info)? >= race_account.lock_time() {
+        return Err(RaceError::RegistrationClosed.into());
+    }
+    if args.slot >= race_account.max_players {
+        return Err(RaceError::SlotOutOfRangeError.into());
+    }
+
+    if RaceEntry::exists(program_id, entry_info)? {
+        return Err(RaceError::PlayerFoundError.into());
+    }
+    if race_account.is_slot_taken(args.slot) {
+        return Err(RaceError::SlotNotAvailableError.into());
+    }
+    let entry_fee = race_account.entry_fee;
+    create_race_entry(
+        program_id,
+        account,
+        entry_info,
+        player_info.key,
+        player_info,
+        system_program_info,
+        rent_info,
+        args.slot,
+        entry_fee,
+    )?;
+    race_account.set_slot_taken(args.slot, true);
+    race_account.player_count += 1;
+    if race_account.player_count() >= race_account.max_players as usize {
+        race_account.set_status(RaceStatus::Full);
+    }
+
+    assert_vault(program_id, account, vault_info)?;
+    deposit_to_vault(
+        &race_account,
+        player_info,
+        vault_info,
+        system_program_info,
+        accounts_iter,
+        entry_fee,
+    )?;
+    race_account.prize_pool = race_account
+        .prize_pool
+        .checked_add(entry_fee)
+        .ok_or(RaceError::NumericalOverflowError)?;
+
+    msg!("Player {} took slot {}", player_info.key, args.slot);
+    Ok(())
+}
+
+pub fn process_sponsor_race(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: SponsorRaceArgs,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let account = next_account_info(accounts_iter)?;
+    let sponsor_info = next_account_info(accounts_iter)?;
+    let vault_info = next_account_info(accounts_iter)?;
+    let system_program_info = next_account_info(accounts_iter)?;
+
+    if account.owner != program_id {
+        msg!("Race Account does not have the correct program id");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    ProgramConfig::from_account_info(program_id, config_info)?;
+
+    let mut race_account = RaceAccount::from_account_info(account)?;
+    if race_account.status()?.is_closed() {
+        return Err(RaceError::RaceClosed.into());
+    }
+
+    assert_vault(program_id, account, vault_info)?;
+    deposit_to_vault(
+        &race_account,
+        sponsor_info,
+        vault_info,
+        system_program_info,
+        accounts_iter,
+        args.amount,
+    )?;
+    race_account.prize_pool = race_account
+        .prize_pool
+        .checked_add(args.amount)
+        .ok_or(RaceError::NumericalOverflowError)?;
+
+    msg!("Prize pool is now {}", race_account.prize_pool);
+    Ok(())
+}
+
+pub fn process_settle_race(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: SettleRaceArgs,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let account = next_account_info(accounts_iter)?;
+    let settler_info = next_account_info(accounts_iter)?;
+    let vault_info = next_account_info(accounts_iter)?;
+    let clock_info = next_account_info(accounts_iter)?;
+
+    if account.owner != program_id {
+        msg!("Race Account does not have the correct program id");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut race_account = RaceAccount::from_account_info(account)?;
+    race_account.assert_settler(settler_info)?;
+    settle(
+        program_id,
+        config_info,
+        account,
+        &mut race_account,
+        vault_info,
+        clock_info,
+        accounts_iter,
+        &args.results,
+    )
+}
+
+pub fn process_submit_result(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: SubmitResultArgs,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let account = next_account_info(accounts_iter)?;
+    let vault_info = next_account_info(accounts_iter)?;
+    let clock_info = next_account_info(accounts_iter)?;
+    let instructions_info = next_account_info(accounts_iter)?;
+
+    if account.owner != program_id {
+        msg!("Race Account does not have the correct program id");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let result_signer = ProgramConfig::from_account_info(program_id, config_info)?
+        .result_signer()
+        .ok_or(RaceError::InvalidResultAttestation)?;
+    let message = result_message(account.key, &args.result_hash, &args.results);
+    assert_result_attestation(instructions_info, &result_signer, &message)?;
+    msg!("Result hash: {:?}", args.result_hash);
+
+    let mut race_account = RaceAccount::from_account_info(account)?;
+    settle(
+        program_id,
+        config_info,
+        account,
+        &mut race_account,
+        vault_info,
+        clock_info,
+        accounts_iter,
+        &args.results,
+    )
+}
+
+/// Record `results` once the game ended, and pay the protocol fee to the treasury and the
+/// rest of the prize pool to the paid places. The accounts left in `accounts_iter` are the
+/// vault token accounts, the treasury payout account and the paid places' entries and payout
+/// accounts.
+#[allow(clippy::too_many_arguments)]
+fn settle<'a, 'b>(
+    program_id: &Pubkey,
+    config_info: &AccountInfo<'a>,
+    account: &AccountInfo<'a>,
+    race_account: &mut RaceAccount,
+    vault_info: &'b AccountInfo<'a>,
+    clock_info: &AccountInfo<'a>,
+    accounts_iter: &mut Iter<'b, AccountInfo<'a>>,
+    results: &[u8],
+) -> ProgramResult {
+    match race_account.status()? {
+        RaceStatus::Settled => return Err(RaceError::RaceAlreadySettled.into()),
+        RaceStatus::Running | RaceStatus::AwaitingResult => (),
+        _ => return Err(RaceError::InvalidStatusTransition.into()),
+    }
+    if race_account.end_date == 0 || unix_timestamp(clock_info)? < race_account.end_date {
+        return Err(RaceError::RaceNotFinished.into());
+    }
+
+    if results.len() != race_account.player_count() {
+        return Err(RaceError::InvalidResults.into());
+    }
+    for (place, slot) in results.iter().enumerate() {
+        if !race_account.is_slot_taken(*slot) || results[..place].contains(slot) {
+            return Err(RaceError::InvalidResults.into());
+        }
+    }
+
+    let treasury = ProgramConfig::from_account_info(program_id, config_info)?.treasury();
+    // Nobody to pay out to, the sponsored prize pool goes to the treasury
+    let protocol_fee = if results.is_empty() {
+        race_account.prize_pool
+    } else {
+        race_account.protocol_fee()
+    };
+    let vault = VaultAccounts::load(program_id, account, race_account, vault_info, accounts_iter)?;
+    let treasury_info = next_account_info(accounts_iter)?;
+    vault.withdraw(program_id, account, race_account, &treasury, treasury_info, protocol_fee)?;
+    msg!("Protocol fee: {}", protocol_fee);
+
+    let paid_places = race_account.payout_table().len().min(results.len());
+    let payouts = split_prize_pool(
+        race_account.prize_pool - protocol_fee,
+        &race_account.payout_table()[..paid_places],
+    )?;
+    for (place, (slot, amount)) in results.iter().zip(payouts).enumerate() {
+        let entry_info = next_account_info(accounts_iter)?;
+        let destination_info = next_account_info(accounts_iter)?;
+        let winner = {
+            let entry = RaceEntry::from_account_info(program_id, account, entry_info)?;
+            if entry.slot != *slot {
+                return Err(RaceError::InvalidResults.into());
+            }
+            entry.player()
+        };
+        vault.withdraw(program_id, account, race_account, &winner, destination_info, amount)?;
+        msg!("Place {}: {} won {}", place + 1, winner, amount);
+    }
+
+    race_account.results[..results.len()].copy_from_slice(results);
+    race_account.results_len = results.len() as u8;
+    race_account.prize_pool = 0;
+    race_account.set_status(RaceStatus::Settled);
+    Ok(())
+}
+
+pub fn process_cancel_race(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let account = next_account_info(accounts_iter)?;
+    let authority_info = next_account_info(accounts_iter)?;
+
+    if account.owner != program_id {
+        msg!("Race Account does not have the correct program id");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    ProgramConfig::from_account_info(program_id, config_info)?;
+
+    let mut race_account = RaceAccount::from_account_info(account)?;
+    race_account.assert_authority(authority_info)?;
+    if race_account.status()?.is_closed() {
+        return Err(RaceError::RaceClosed.into());
+    }
+
+    race_account.set_status(RaceStatus::Cancelled);
+    Ok(())
+}
+
+pub fn process_claim_refund(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let account = next_account_info(accounts_iter)?;
+    let player_info = next_account_info(accounts_iter)?;
+    let entry_info = next_account_info(accounts_iter)?;
+    let vault_info = next_account_info(accounts_iter)?;
+
+    if account.owner != program_id {
+        msg!("Race Account does not have the correct program id");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    ProgramConfig::from_account_info(program_id, config_info)?;
+
+    if !player_info.is_signer {
+        return Err(RaceError::PlayerIsNotSigner.into());
+    }
+
+    let mut race_account = RaceAccount::from_account_info(account)?;
+    if race_account.status()? != RaceStatus::Cancelled {
+        return Err(RaceError::RaceNotCancelled.into());
+    }
+
+    let vault = VaultAccounts::load(program_id, account, &race_account, vault_info, accounts_iter)?;
+    let destination_info = if race_account.fee_mint().is_some() {
+        next_account_info(accounts_iter)?
+    } else {
+        player_info
+    };
+
+    let mut entry = RaceEntry::from_account_info(program_id, account, entry_info)?;
+    if entry.player() != *player_info.key {
+        return Err(RaceError::PlayerNotFound.into());
+    }
+    if entry.refunded != 0 {
+        return Err(RaceError::RefundAlreadyClaimed.into());
+    }
+    entry.refunded = 1;
+    let refund = entry.fee_paid;
+
+    race_account.prize_pool = race_account
+        .prize_pool
+        .checked_sub(refund)
+        .ok_or(RaceError::NumericalOverflowError)?;
+    vault.withdraw(program_id, account, &race_account, player_info.key, destination_info, refund)?;
+
+    msg!("Refunded {} to {}", refund, player_info.key);
+    Ok(())
+}
+
+pub fn process_leave_race(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let account = next_account_info(accounts_iter)?;
+    let player_info = next_account_info(accounts_iter)?;
+    let entry_info = next_account_info(accounts_iter)?;
+    let vault_info = next_account_info(accounts_iter)?;
+    let clock_info = next_account_info(accounts_iter)?;
+
+    if account.owner != program_id {
+        msg!("Race Account does not have the correct program id");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    ProgramConfig::from_account_info(program_id, config_info)?;
+
+    if !player_info.is_signer {
+        return Err(RaceError::PlayerIsNotSigner.into());
+    }
+
+    let mut race_account = RaceAccount::from_account_info(account)?;
+    let status = race_account.status()?;
+    if !matches!(status, RaceStatus::Open | RaceStatus::Full)
+        || unix_timestamp(clock_info)? >= race_account.lock_time()
+    {
+        return Err(RaceError::RaceLocked.into());
+    }
+
+    let vault = VaultAccounts::load(program_id, account, &race_account, vault_info, accounts_iter)?;
+    let destination_info = if race_account.fee_mint().is_some() {
+        next_account_info(accounts_iter)?
+    } else {
+        player_info
+    };
+
+    let (slot, fee_paid) = {
+        let entry = RaceEntry::from_account_info(program_id, account, entry_info)?;
+        if entry.player() != *player_info.key {
+            return Err(RaceError::PlayerNotFound.into());
+        }
+        (entry.slot, entry.fee_paid)
+    };
+    close_race_entry(entry_info, player_info)?;
+    race_account.set_slot_taken(slot, false);
+    race_account.player_count -= 1;
+
+    let withdrawal_fee = (fee_paid as u128 * race_account.withdrawal_fee_bps as u128
+        / PAYOUT_BPS as u128) as u64;
+    let refund = fee_paid - withdrawal_fee;
+    race_account.prize_pool = race_account
+        .prize_pool
+        .checked_sub(refund)
+        .ok_or(RaceError::NumericalOverflowError)?;
+    if status == RaceStatus::Full {
+        race_account.set_status(RaceStatus::Open);
+    }
+    vault.withdraw(program_id, account, &race_account, player_info.key, destination_info, refund)?;
+
+    msg!("Player {} left slot {}, refunded {}", player_info.key, slot, refund);
+    Ok(())
+}
+
+pub fn process_check_race_start(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let account = next_account_info(accounts_iter)?;
+    let clock_info = next_account_info(accounts_iter)?;
+
+    if account.owner != program_id {
+        msg!("Race Account does not have the correct program id");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let paused = ProgramConfig::from_account_info(program_id, config_info)?.is_paused();
+
+    let mut race_account = RaceAccount::from_account_info(account)?;
+    if !matches!(
+        race_account.status()?,
+        RaceStatus::Open | RaceStatus::Full | RaceStatus::Closed
+    ) {
+        return Err(RaceError::InvalidStatusTransition.into());
+    }
+    if unix_timestamp(clock_info)? < race_account.date {
+        return Err(RaceError::RaceNotStarted.into());
+    }
+    // Cancelling gets the players their entry fees back, starting waits for the resume
+    if paused && race_account.player_count >= race_account.min_players {
+        return Err(RaceError::ProgramPaused.into());
+    }
+
+    race_account.start_or_cancel();
+    Ok(())
+}
+
+pub fn process_advance_race(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let account = next_account_info(accounts_iter)?;
+    let vault_info = next_account_info(accounts_iter)?;
+    let caller_info = next_account_info(accounts_iter)?;
+    let clock_info = next_account_info(accounts_iter)?;
+    let rent_info = next_account_info(accounts_iter)?;
+
+    if account.owner != program_id {
+        msg!("Race Account does not have the correct program id");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if !caller_info.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let crank_reward = ProgramConfig::from_account_info(program_id, config_info)?.crank_reward;
+
+    let mut race_account = RaceAccount::from_account_info(account)?;
+    let now = unix_timestamp(clock_info)?;
+    let initial_status = race_account.status()?;
+    loop {
+        match race_account.status()? {
+            RaceStatus::Open | RaceStatus::Full if now >= race_account.lock_time() => {
+                race_account.set_status(RaceStatus::Closed)
+            }
+            RaceStatus::Closed if now >= race_account.date => race_account.start_or_cancel(),
+            RaceStatus::Running if race_account.end_date != 0 && now >= race_account.end_date => {
+                race_account.set_status(RaceStatus::AwaitingResult)
+            }
+            _ => break,
+        }
+    }
+    let status = race_account.status()?;
+    if status == initial_status {
+        return Err(RaceError::RaceNotReady.into());
+    }
+    msg!("Race advanced from {:?} to {:?}", initial_status, status);
+
+    // Only lamports beyond rent exemption and the prize pool are spent on rewards
+    assert_vault(program_id, account, vault_info)?;
+    if vault_info.owner != program_id {
+        return Err(RaceError::InvalidVaultKey.into());
+    }
+    let rent = &Rent::from_account_info(rent_info)?;
+    let mut reserved = rent.minimum_balance(vault_info.data_len());
+    if race_account.fee_mint().is_none() {
+        reserved = reserved.saturating_add(race_account.prize_pool);
+    }
+    let reward = vault_info.lamports().saturating_sub(reserved).min(crank_reward);
+    if reward > 0 {
+        **vault_info.lamports.borrow_mut() -= reward;
+        let caller_lamports = caller_info.lamports();
+        **caller_info.lamports.borrow_mut() = caller_lamports
+            .checked_add(reward)
+            .ok_or(RaceError::NumericalOverflowError)?;
+        msg!("Crank reward: {}", reward);
+    }
+    Ok(())
+}
+
+pub fn process_migrate_race(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let account = next_account_info(accounts_iter)?;
+    let payer_info = next_account_info(accounts_iter)?;
+    let system_program_info = next_account_info(accounts_iter)?;
+    let rent_info = next_account_info(accounts_iter)?;
+
+    if account.owner != program_id {
+        msg!("Race Account does not have the correct program id");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let protocol_fee_bps = ProgramConfig::from_account_info(program_id, config_info)?.protocol_fee_bps;
+
+    if !payer_info.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *system_program_info.key != system_program::id() {
+        return Err(RaceError::InvalidSystemProgram.into());
+    }
+
+    // Program owned accounts cannot be resized by this runtime, a layout may only shrink
+    // and older accounts keep their size, the bytes past RACE_ACCOUNT_LEN are cleared
+    if account.data_len() < RACE_ACCOUNT_LEN {
+        msg!("Race account is {} bytes, {} needed", account.data_len(), RACE_ACCOUNT_LEN);
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+
+    let rent = &Rent::from_account_info(rent_info)?;
+    let required_lamports = rent
+        .minimum_balance(account.data_len())
+        .saturating_sub(account.lamports());
+    if required_lamports > 0 {
+        msg!("Transfer {} lamports to the race account", required_lamports);
+        invoke(
+            &system_instruction::transfer(payer_info.key, account.key, required_lamports),
+            &[
+                payer_info.clone(),
+                account.clone(),
+                system_program_info.clone(),
+            ],
+        )?;
+    }
+
+    {
+        let race_account = RaceAccount::from_account_info_unchecked(account)?;
+        match Key::from_u8(race_account.key) {
+            // Version 1 kept the version in the first byte and left the version byte as padding
+            Some(Key::RaceAccountV1) => (),
+            Some(Key::RaceAccount) if race_account.version == 2 => (),
+            Some(Key::RaceAccount) if race_account.version == RACE_ACCOUNT_VERSION => {
+                return Err(RaceError::RaceAlreadyMigrated.into())
+            }
+            _ => return Err(RaceError::DataTypeMismatch.into()),
+        }
+    }
+    let legacy_players = read_legacy_players(account)?;
+
+    // Versions 1 and 2 kept the players in the race account, give each of them a race entry
+    for (slot, player) in legacy_players.iter() {
+        let entry_info = next_account_info(accounts_iter)?;
+        create_race_entry(
+            program_id,
+            account,
+            entry_info,
+            &Pubkey::new_from_array(player.address),
+            payer_info,
+            system_program_info,
+            rent_info,
+            *slot,
+            player.fee_paid,
+        )?;
+        RaceEntry::from_account_info(program_id, account, entry_info)?.refunded = player.refunded;
+    }
+
+    for byte in account.data.borrow_mut()[RACE_ACCOUNT_LEN..].iter_mut() {
+        *byte = 0;
+    }
+    let mut race_account = RaceAccount::from_account_info_unchecked(account)?;
+    // The old results started at the same offset, past them the bytes held the player table
+    let results_len = race_account.results_len.min(LEGACY_MAX_PLAYERS as u8);
+    race_account.results_len = results_len;
+    race_account.results[results_len as usize..].fill(0);
+    race_account.min_players = 0;
+    // Older layouts had no fee of their own, the races settle with the current one
+    race_account.protocol_fee_bps = protocol_fee_bps;
+    race_account._padding2 = [0; 2];
+    race_account.slots = [0; SLOT_BITMAP_LEN];
+    for (slot, _) in legacy_players.iter() {
+        race_account.set_slot_taken(*slot, true);
+    }
+    race_account.key = Key::RaceAccount as u8;
+    race_account.version = RACE_ACCOUNT_VERSION;
+    msg!("Race account migrated to version {}", RACE_ACCOUNT_VERSION);
+    Ok(())
+}
+
+
+pub fn process_initialize_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: InitializeConfigArgs,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let payer_info = next_account_info(accounts_iter)?;
+    let admin_info = next_account_info(accounts_iter)?;
+    let system_program_info = next_account_info(accounts_iter)?;
+    let rent_info = next_account_info(accounts_iter)?;
+    let program_data_info = next_account_info(accounts_iter)?;
+    let upgrade_authority_info = next_account_info(accounts_iter)?;
+
+    if !payer_info.is_signer || !admin_info.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    // The config is created once, only whoever deployed the program may pick its admin
+    assert_upgrade_authority(program_id, program_data_info, upgrade_authority_info)?;
+
+    let (config_key, bump_seed) = find_config_address(program_id);
+    if config_key != *config_info.key {
+        return Err(RaceError::InvalidConfigKey.into());
+    }
+
+    if config_info.owner == program_id || !config_info.data_is_empty() {
+        return Err(RaceError::AlreadyInitialized.into());
+    }
+
+    let config_signer_seeds = &[
+        PREFIX.as_bytes(),
+        program_id.as_ref(),
+        CONFIG.as_bytes(),
+        &[bump_seed],
+    ];
+    create_or_allocate_account_raw(
+        *program_id,
+        config_info,
+        rent_info,
+        system_program_info,
+        payer_info,
+        PROGRAM_CONFIG_LEN,
+        config_signer_seeds,
+    )?;
+
+    let mut config = ProgramConfig::from_account_info_unchecked(config_info)?;
+    config.key = Key::ProgramConfig as u8;
+    config.version = PROGRAM_CONFIG_VERSION;
+    config.admin = admin_info.key.to_bytes();
+    config.set_protocol_fee_bps(args.protocol_fee_bps)?;
+    config.treasury = args.treasury.to_bytes();
+    config.max_entry_fee = args.max_entry_fee;
+    config.max_players = args.max_players;
+    config.crank_reward = args.crank_reward;
+    config.result_signer = args.result_signer.unwrap_or_default().to_bytes();
+    config.set_allowed_programs(&args.allowed_programs)?;
+    Ok(())
+}
+
+pub fn process_update_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: UpdateConfigArgs,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let admin_info = next_account_info(accounts_iter)?;
+
+    let mut config = ProgramConfig::from_account_info(program_id, config_info)?;
+    config.assert_admin(admin_info)?;
+    if let Some(admin) = args.admin {
+        msg!("New admin: {}", admin);
+        config.admin = admin.to_bytes();
+    }
+    if let Some(protocol_fee_bps) = args.protocol_fee_bps {
+        config.set_protocol_fee_bps(protocol_fee_bps)?;
+    }
+    if let Some(treasury) = args.treasury {
+        config.treasury = treasury.to_bytes();
+    }
+    if let Some(paused) = args.paused {
+        config.paused = paused as u8;
+    }
+    if let Some(max_entry_fee) = args.max_entry_fee {
+        config.max_entry_fee = max_entry_fee;
+    }
+    if let Some(max_players) = args.max_players {
+        config.max_players = max_players;
+    }
+    if let Some(crank_reward) = args.crank_reward {
+        config.crank_reward = crank_reward;
+    }
+    if let Some(result_signer) = args.result_signer {
+        config.result_signer = result_signer.to_bytes();
+    }
+    Ok(())
+}
+
+pub fn process_set_allowed_programs(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: SetAllowedProgramsArgs,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let admin_info = next_account_info(accounts_iter)?;
+
+    let mut config = ProgramConfig::from_account_info(program_id, config_info)?;
+    config.assert_admin(admin_info)?;
+    config.set_allowed_programs(&args.allowed_programs)?;
+    msg!("{} programs allowed", config.allowed_program_count);
+    Ok(())
+}
+
+pub fn process_set_paused(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: SetPausedArgs,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let config_info = next_account_info(accounts_iter)?;
+    let admin_info = next_account_info(accounts_iter)?;
+
+    let mut config = ProgramConfig::from_account_info(program_id, config_info)?;
+    config.assert_admin(admin_info)?;
+    config.paused = args.paused as u8;
+    Ok(())
+}
+
+/// Fails with ProgramPaused while the config is paused. Race instructions take the config
+/// first, CPI instructions right after the invoking program and its CPI authority.
+fn assert_not_paused(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction: &RaceInstruction,
+) -> ProgramResult {
+    let config_index = match instruction {
+        RaceInstruction::CpiJoinRace(_) | RaceInstruction::CpiLeaveRace => 2,
+        _ => 0,
+    };
+    let config_info = accounts
+        .get(config_index)
+        .ok_or(ProgramError::NotEnoughAccountKeys)?;
+    if ProgramConfig::from_account_info(program_id, config_info)?.is_paused() {
+        return Err(RaceError::ProgramPaused.into());
+    }
+    Ok(())
+}
+
+/// Checks the leading accounts of a CPI instruction, an allowed invoking program and its
+/// CPI authority signer. Returns the accounts of the wrapped instruction, which start with
+/// the config.
+fn assert_cpi_caller<'a, 'b>(
+    program_id: &Pubkey,
+    accounts: &'a [AccountInfo<'b>],
+) -> Result<&'a [AccountInfo<'b>], ProgramError> {
+    let accounts_iter = &mut accounts.iter();
+
+    let caller_program_info = next_account_info(accounts_iter)?;
+    let cpi_authority_info = next_account_info(accounts_iter)?;
+    let config_info = next_account_info(accounts_iter)?;
+
+    let config = ProgramConfig::from_account_info(program_id, config_info)?;
+    if !config.is_allowed_program(caller_program_info.key) {
+        msg!("Program {} is not allowed", caller_program_info.key);
+        return Err(RaceError::CallerNotAllowed.into());
+    }
+
+    // Only the invoking program can sign for a PDA derived from its id
+    let (cpi_authority, _) = find_cpi_authority_address(caller_program_info.key);
+    if cpi_authority != *cpi_authority_info.key || !cpi_authority_info.is_signer {
+        return Err(RaceError::InvalidCpiAuthority.into());
+    }
+
+    Ok(&accounts[2..])
+}
+
+/// Checks the instructions sysvar passed last to JoinRace and LeaveRace. Invoking programs
+/// must go through the CPI instructions, so the current top-level instruction has to be
+/// this program's. Returns the accounts before the sysvar.
+fn assert_top_level_caller<'a, 'b>(
+    program_id: &Pubkey,
+    accounts: &'a [AccountInfo<'b>],
+) -> Result<&'a [AccountInfo<'b>], ProgramError> {
+    let (instructions_info, race_accounts) = accounts
+        .split_last()
+        .ok_or(ProgramError::NotEnoughAccountKeys)?;
+    if *instructions_info.key != sysvar::instructions::id() {
+        msg!("Instructions sysvar expected");
+        return Err(RaceError::CallerNotAllowed.into());
+    }
+    let data = instructions_info.data.borrow();
+    let top_level = load_instruction_at(load_current_index(&data) as usize, &data)
+        .map_err(|_| ProgramError::InvalidAccountData)?;
+    if top_level.program_id != *program_id {
+        msg!("Invoked by {}, programs must use the CPI instructions", top_level.program_id);
+        return Err(RaceError::CallerNotAllowed.into());
+    }
+    Ok(race_accounts)
+}
+
+
+// Sanity tests
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        instruction::{
+            initialize_config, submit_result, verify_ed25519_signature, JoinRaceArgs,
+            UpdateGameArgs,
+        },
+        state::{
+            find_race_entry_address, find_vault_address, LegacyPlayer, LEGACY_PLAYERS_OFFSET,
+            MAX_ALLOWED_PROGRAMS, MIN_DISTANCE, RACE_ENTRY_LEN,
+        },
+    };
+    use borsh::BorshSerialize;
+    use solana_program::{
+        bpf_loader_upgradeable,
+        clock::{Clock, Epoch, UnixTimestamp},
+        instruction::{AccountMeta, Instruction},
+        message::Message,
+        program_pack::Pack,
+        program_stubs, sysvar,
+    };
+    use spl_associated_token_account::get_associated_token_address;
+    use std::{cell::RefCell, mem::size_of};
+
+    #[test]
+    fn test_sanity() {
+        let program_id = Pubkey::new_unique();
+        let key = Pubkey::default();
+        let mut lamports = 0;
+        let mut data = vec![0; RACE_ACCOUNT_LEN];
+        let authority_key = Pubkey::new_unique();
+        {
+            let race_account = bytemuck::from_bytes_mut::<RaceAccount>(&mut data[..]);
+            race_account.key = Key::RaceAccount as u8;
+            race_account.version = RACE_ACCOUNT_VERSION;
+            race_account.authority = authority_key.to_bytes();
+            race_account.level = 1;
+            race_account.set_name("Race").unwrap();
+            race_account.distance = 1200;
+            race_account.max_players = 2;
+            race_account.set_payout_table(&[PAYOUT_BPS]).unwrap();
+            race_account.protocol_fee_bps = 1_000;
+        }
+        let owner = program_id;
+        let account = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let (config_key, _) = find_config_address(&program_id);
+        let mut config_lamports = 0;
+        let mut config_data = vec![0; PROGRAM_CONFIG_LEN];
+        let treasury_key = Pubkey::new_unique();
+        {
+            let config = bytemuck::from_bytes_mut::<ProgramConfig>(&mut config_data[..]);
+            config.key = Key::ProgramConfig as u8;
+            config.version = PROGRAM_CONFIG_VERSION;
+            config.set_protocol_fee_bps(1_000).unwrap();
+            config.treasury = treasury_key.to_bytes();
+            config.max_entry_fee = 100;
+        }
+        let config = AccountInfo::new(
+            &config_key,
+            false,
+            false,
+            &mut config_lamports,
+            &mut config_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let mut authority_lamports = 0;
+        let mut authority_data = vec![];
+        let authority = AccountInfo::new(
+            &authority_key,
+            true,
+            false,
+            &mut authority_lamports,
+            &mut authority_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let update_race_args = UpdateRaceArgs {
+            status: Some(RaceStatus::Open),
+            level: Some(2),
+            r#type: Some(1),
+            date: Some(1_000),
+            name: Some("Derby".to_string()),
+            location: Some("Sha Tin".to_string()),
+            distance: Some(1600),
+            entry_fee: Some(10),
+            payout_table: Some(vec![6_000, 3_000, 1_000]),
+            lock_period: Some(0),
+            withdrawal_fee_bps: Some(0),
+            min_players: Some(1),
+        };
+        let instruction_data = RaceInstruction::UpdateRace(update_race_args.clone())
+            .try_to_vec()
+            .unwrap();
+
+        let mut unsigned_authority = authority.clone();
+        unsigned_authority.is_signer = false;
+        let unsigned_accounts = vec![config.clone(), account.clone(), unsigned_authority];
+        assert_eq!(
+            process_instruction(&program_id, &unsigned_accounts, &instruction_data),
+            Err(RaceError::AuthorityIsNotSigner.into())
+        );
+
+        let accounts = vec![config.clone(), account, authority];
+        let expensive_race_data = RaceInstruction::UpdateRace(UpdateRaceArgs {
+            entry_fee: Some(101),
+            ..update_race_args.clone()
+        })
+        .try_to_vec()
+        .unwrap();
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &expensive_race_data),
+            Err(RaceError::EntryFeeTooHigh.into())
+        );
+
+        let empty_update_data = RaceInstruction::UpdateRace(UpdateRaceArgs::default())
+            .try_to_vec()
+            .unwrap();
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &empty_update_data),
+            Err(RaceError::EmptyUpdate.into())
+        );
+        let short_race_data = RaceInstruction::UpdateRace(UpdateRaceArgs {
+            distance: Some(MIN_DISTANCE - 1),
+            ..UpdateRaceArgs::default()
+        })
+        .try_to_vec()
+        .unwrap();
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &short_race_data),
+            Err(RaceError::InvalidDistance.into())
+        );
+
+        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+        {
+            let race_account = RaceAccount::from_account_info(&accounts[1]).unwrap();
+            assert_eq!(race_account.name(), "Derby");
+            assert_eq!(race_account.r#type, 1);
+            assert_eq!(race_account.distance, 1600);
+            assert_eq!(race_account.player_count(), 0);
+        }
+
+        let (vault_key, _) = find_vault_address(&program_id, &key);
+        let mut vault_lamports = 20;
+        let mut vault_data = vec![];
+        let vault = AccountInfo::new(
+            &vault_key,
+            false,
+            true,
+            &mut vault_lamports,
+            &mut vault_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let system_program_key = system_program::id();
+        let mut system_program_lamports = 0;
+        let mut system_program_data = vec![];
+        let system_program = AccountInfo::new(
+            &system_program_key,
+            false,
+            false,
+            &mut system_program_lamports,
+            &mut system_program_data,
+            &owner,
+            true,
+            Epoch::default(),
+        );
+        let clock_key = sysvar::clock::id();
+        let mut clock_lamports = 0;
+        let mut clock_data = vec![0; Clock::size_of()];
+        let mut clock = AccountInfo::new(
+            &clock_key,
+            false,
+            false,
+            &mut clock_lamports,
+            &mut clock_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        Clock {
+            unix_timestamp: 500,
+            ..Clock::default()
+        }
+        .to_account_info(&mut clock)
+        .unwrap();
+
+        let rent_key = sysvar::rent::id();
+        let mut rent_lamports = 0;
+        let mut rent_data = vec![0; Rent::size_of()];
+        let mut rent = AccountInfo::new(
+            &rent_key,
+            false,
+            false,
+            &mut rent_lamports,
+            &mut rent_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        Rent::default().to_account_info(&mut rent).unwrap();
+        let instructions_key = sysvar::instructions::id();
+        let mut instructions_lamports = 0;
+        let mut instructions_data = top_level_instructions_data(&program_id);
+        let instructions = AccountInfo::new(
+            &instructions_key,
+            false,
+            false,
+            &mut instructions_lamports,
+            &mut instructions_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+
+        let (authority_entry_key, _) = find_race_entry_address(&program_id, &key, &authority_key);
+        let mut authority_entry_lamports = 0;
+        let mut authority_entry_data = vec![0; RACE_ENTRY_LEN];
+        let authority_entry = AccountInfo::new(
+            &authority_entry_key,
+            false,
+            true,
+            &mut authority_entry_lamports,
+            &mut authority_entry_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+
+        let authority_account = accounts[2].clone();
+        let accounts = vec![
+            config.clone(),
+            accounts[1].clone(),
+            accounts[2].clone(),
+            authority_entry.clone(),
+            vault.clone(),
+            system_program.clone(),
+            clock.clone(),
+            rent.clone(),
+            instructions.clone(),
+        ];
+        let instruction_data = RaceInstruction::JoinRace(JoinRaceArgs { slot: 1 })
+            .try_to_vec()
+            .unwrap();
+        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction_data),
+            Err(RaceError::PlayerFoundError.into())
+        );
+
+        let mut player_lamports = 0;
+        let mut player_data = vec![];
+        let player_key = Pubkey::new_unique();
+        let player = AccountInfo::new(
+            &player_key,
+            true,
+            true,
+            &mut player_lamports,
+            &mut player_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let (player_entry_key, _) = find_race_entry_address(&program_id, &key, &player_key);
+        let mut player_entry_lamports = 0;
+        let mut player_entry_data = vec![0; RACE_ENTRY_LEN];
+        let player_entry = AccountInfo::new(
+            &player_entry_key,
+            false,
+            true,
+            &mut player_entry_lamports,
+            &mut player_entry_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let accounts = vec![
+            config.clone(),
+            accounts[1].clone(),
+            player,
+            player_entry,
+            vault,
+            system_program,
+            clock,
+            rent,
+            instructions,
+        ];
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction_data),
+            Err(RaceError::SlotNotAvailableError.into())
+        );
+        let instruction_data = RaceInstruction::JoinRace(JoinRaceArgs { slot: 0 })
+            .try_to_vec()
+            .unwrap();
+        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+        {
+            let race_account = RaceAccount::from_account_info(&accounts[1]).unwrap();
+            assert_eq!(race_account.prize_pool, 20);
+            assert_eq!(race_account.status().unwrap(), RaceStatus::Full);
+            assert!(race_account.is_slot_taken(0));
+            assert!(race_account.is_slot_taken(1));
+            let entry = RaceEntry::from_account_info(&program_id, &accounts[1], &accounts[3]).unwrap();
+            assert_eq!(entry.player(), player_key);
+            assert_eq!(entry.slot, 0);
+            assert_eq!(entry.fee_paid, 10);
+        }
+
+        let instruction_data = RaceInstruction::UpdateGame(UpdateGameArgs {
+            game_url: "https://darleygo.io".to_string(),
+            end_date: 500,
+            oracle: None,
+        })
+        .try_to_vec()
+        .unwrap();
+        let update_accounts = vec![
+            config.clone(),
+            accounts[1].clone(),
+            authority_account.clone(),
+            accounts[6].clone(),
+        ];
+        process_instruction(&program_id, &update_accounts, &instruction_data).unwrap();
+        assert_eq!(
+            process_instruction(&program_id, &update_accounts, &instruction_data),
+            Err(RaceError::GameEnded.into())
+        );
+
+        let update_accounts = vec![config.clone(), accounts[1].clone(), authority_account.clone()];
+        assert_eq!(
+            process_instruction(&program_id, &update_accounts, &expensive_race_data),
+            Err(RaceError::TermsLocked.into())
+        );
+        let instruction_data = RaceInstruction::UpdateRace(UpdateRaceArgs {
+            distance: Some(2000),
+            ..UpdateRaceArgs::default()
+        })
+        .try_to_vec()
+        .unwrap();
+        assert_eq!(
+            process_instruction(&program_id, &update_accounts, &instruction_data),
+            Err(RaceError::TermsLocked.into())
+        );
+        // Only the cranks start a race, once its date is reached
+        let instruction_data = RaceInstruction::UpdateRace(UpdateRaceArgs {
+            status: Some(RaceStatus::Running),
+            ..UpdateRaceArgs::default()
+        })
+        .try_to_vec()
+        .unwrap();
+        assert_eq!(
+            process_instruction(&program_id, &update_accounts, &instruction_data),
+            Err(RaceError::InvalidStatusTransition.into())
+        );
+        let instruction_data = RaceInstruction::UpdateRace(UpdateRaceArgs {
+            location: Some("Happy Valley".to_string()),
+            ..UpdateRaceArgs::default()
+        })
+        .try_to_vec()
+        .unwrap();
+        process_instruction(&program_id, &update_accounts, &instruction_data).unwrap();
+        {
+            let race_account = RaceAccount::from_account_info(&accounts[1]).unwrap();
+            assert_eq!(race_account.name(), "Derby");
+            assert_eq!(race_account.location(), "Happy Valley");
+            assert_eq!(race_account.entry_fee, 10);
+        }
+
+        let mut clock = accounts[6].clone();
+        Clock {
+            unix_timestamp: 1_000,
+            ..Clock::default()
+        }
+        .to_account_info(&mut clock)
+        .unwrap();
+        let start_accounts = vec![config.clone(), accounts[1].clone(), clock];
+        let instruction_data = RaceInstruction::CheckRaceStart.try_to_vec().unwrap();
+        process_instruction(&program_id, &start_accounts, &instruction_data).unwrap();
+        assert_eq!(
+            RaceAccount::from_account_info(&accounts[1]).unwrap().status().unwrap(),
+            RaceStatus::Running
+        );
+
+        let mut treasury_lamports = 0;
+        let mut treasury_data = vec![];
+        let treasury = AccountInfo::new(
+            &treasury_key,
+            false,
+            true,
+            &mut treasury_lamports,
+            &mut treasury_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let accounts = vec![
+            config,
+            accounts[1].clone(),
+            authority_account.clone(),
+            accounts[4].clone(),
+            accounts[6].clone(),
+            treasury,
+            accounts[3].clone(),
+            accounts[2].clone(),
+            authority_entry,
+            authority_account,
+        ];
+        // Raising the config fee once players paid in leaves the race on its own fee
+        ProgramConfig::from_account_info(&program_id, &accounts[0])
+            .unwrap()
+            .set_protocol_fee_bps(PAYOUT_BPS)
+            .unwrap();
+        let instruction_data = RaceInstruction::SettleRace(SettleRaceArgs { results: vec![0, 1] })
+            .try_to_vec()
+            .unwrap();
+        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+        // 10% of the prize pool goes to the treasury, the rest is split 60/30
+        assert_eq!(accounts[5].lamports(), 2);
+        assert_eq!(accounts[2].lamports(), 6);
+        assert_eq!(accounts[3].lamports(), 0);
+        assert_eq!(accounts[7].lamports(), 12);
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction_data),
+            Err(RaceError::RaceAlreadySettled.into())
+        );
+    }
+
+    /// Instructions sysvar data of a transaction whose current instruction is a top-level
+    /// instruction of `program_id`
+    fn top_level_instructions_data(program_id: &Pubkey) -> Vec<u8> {
+        let instruction = Instruction::new_with_bytes(*program_id, &[], vec![]);
+        let mut data = Message::new(&[instruction], None).serialize_instructions();
+        data.extend_from_slice(&0u16.to_le_bytes());
+        data
+    }
+
+    thread_local! {
+        /// Instructions invoked by the current test thread
+        static INVOKED: RefCell<Vec<Instruction>> = const { RefCell::new(vec![]) };
+    }
+
+    /// Syscall stubs recording invoked instructions, which are not run outside the runtime
+    struct RecordingStubs;
+
+    impl program_stubs::SyscallStubs for RecordingStubs {
+        fn sol_invoke_signed(
+            &self,
+            instruction: &Instruction,
+            _account_infos: &[AccountInfo],
+            _signers_seeds: &[&[&[u8]]],
+        ) -> ProgramResult {
+            INVOKED.with(|invoked| invoked.borrow_mut().push(instruction.clone()));
+            Ok(())
+        }
+    }
+
+    /// Account for the processor tests, its key, lamports and data are leaked so a test can
+    /// hold as many accounts as it needs without a binding for each
+    fn new_account(
+        key: Pubkey,
+        is_signer: bool,
+        is_writable: bool,
+        lamports: u64,
+        data: Vec<u8>,
+        owner: &Pubkey,
+    ) -> AccountInfo<'static> {
+        AccountInfo::new(
+            Box::leak(Box::new(key)),
+            is_signer,
+            is_writable,
+            Box::leak(Box::new(lamports)),
+            Box::leak(data.into_boxed_slice()),
+            Box::leak(Box::new(*owner)),
+            false,
+            Epoch::default(),
+        )
+    }
+
+    /// Program config on the current layout, `init` sets the fields the test depends on
+    fn new_config(
+        program_id: &Pubkey,
+        init: impl FnOnce(&mut ProgramConfig),
+    ) -> AccountInfo<'static> {
+        let mut data = vec![0; PROGRAM_CONFIG_LEN];
+        {
+            let config = bytemuck::from_bytes_mut::<ProgramConfig>(&mut data[..]);
+            config.key = Key::ProgramConfig as u8;
+            config.version = PROGRAM_CONFIG_VERSION;
+            init(config);
+        }
+        let (config_key, _) = find_config_address(program_id);
+        new_account(config_key, false, true, 0, data, program_id)
+    }
+
+    /// Race account on the current layout, `init` sets the fields the test depends on
+    fn new_race(
+        program_id: &Pubkey,
+        key: Pubkey,
+        init: impl FnOnce(&mut RaceAccount),
+    ) -> AccountInfo<'static> {
+        let mut data = vec![0; RACE_ACCOUNT_LEN];
+        {
+            let race_account = bytemuck::from_bytes_mut::<RaceAccount>(&mut data[..]);
+            race_account.key = Key::RaceAccount as u8;
+            race_account.version = RACE_ACCOUNT_VERSION;
+            init(race_account);
+        }
+        new_account(key, false, true, 0, data, program_id)
+    }
+
+    /// Clock sysvar account reading `unix_timestamp`
+    fn new_clock(unix_timestamp: UnixTimestamp) -> AccountInfo<'static> {
+        let clock = new_account(
+            sysvar::clock::id(),
+            false,
+            false,
+            0,
+            vec![0; Clock::size_of()],
+            &sysvar::id(),
+        );
+        set_clock(&clock, unix_timestamp);
+        clock
+    }
+
+    fn set_clock(clock: &AccountInfo, unix_timestamp: UnixTimestamp) {
+        Clock {
+            unix_timestamp,
+            ..Clock::default()
+        }
+        .to_account_info(&mut clock.clone())
+        .unwrap();
+    }
+
+    #[test]
+    fn test_leave_and_rejoin() {
+        program_stubs::set_syscall_stubs(Box::new(RecordingStubs));
+        let program_id = Pubkey::new_unique();
+        let (config_key, _) = find_config_address(&program_id);
+        let mut config_lamports = 0;
+        let mut config_data = vec![0; PROGRAM_CONFIG_LEN];
+        {
+            let config = bytemuck::from_bytes_mut::<ProgramConfig>(&mut config_data[..]);
+            config.key = Key::ProgramConfig as u8;
+            config.version = PROGRAM_CONFIG_VERSION;
+        }
+        let config = AccountInfo::new(
+            &config_key,
+            false,
+            false,
+            &mut config_lamports,
+            &mut config_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut data = vec![0; RACE_ACCOUNT_LEN];
+        {
+            let race_account = bytemuck::from_bytes_mut::<RaceAccount>(&mut data[..]);
+            race_account.key = Key::RaceAccount as u8;
+            race_account.version = RACE_ACCOUNT_VERSION;
+            race_account.set_status(RaceStatus::Open);
+            race_account.date = 1_000;
+            race_account.max_players = 2;
+            race_account.player_count = 1;
+            race_account.set_slot_taken(0, true);
+            race_account.entry_fee = 10;
+            race_account.prize_pool = 10;
+        }
+        let account = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let player_key = Pubkey::new_unique();
+        let mut player_lamports = 0;
+        let mut player_data = vec![];
+        let player = AccountInfo::new(
+            &player_key,
+            true,
+            true,
+            &mut player_lamports,
+            &mut player_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let entry_rent = Rent::default().minimum_balance(RACE_ENTRY_LEN);
+        let (entry_key, bump_seed) = find_race_entry_address(&program_id, &key, &player_key);
+        let mut entry_lamports = entry_rent;
+        let mut entry_data = vec![0; RACE_ENTRY_LEN];
+        {
+            let entry = bytemuck::from_bytes_mut::<RaceEntry>(&mut entry_data[..]);
+            entry.key = Key::RaceEntry as u8;
+            entry.bump_seed = bump_seed;
+            entry.race = key.to_bytes();
+            entry.player = player_key.to_bytes();
+            entry.fee_paid = 10;
+        }
+        let entry = AccountInfo::new(
+            &entry_key,
+            false,
+            true,
+            &mut entry_lamports,
+            &mut entry_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let (vault_key, _) = find_vault_address(&program_id, &key);
+        let mut vault_lamports = 10;
+        let mut vault_data = vec![];
+        let vault = AccountInfo::new(
+            &vault_key,
+            false,
+            true,
+            &mut vault_lamports,
+            &mut vault_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let system_program_key = system_program::id();
+        let mut system_program_lamports = 0;
+        let mut system_program_data = vec![];
+        let system_program = AccountInfo::new(
+            &system_program_key,
+            false,
+            false,
+            &mut system_program_lamports,
+            &mut system_program_data,
+            &program_id,
+            true,
+            Epoch::default(),
+        );
+        let clock_key = sysvar::clock::id();
+        let mut clock_lamports = 0;
+        let mut clock_data = vec![0; Clock::size_of()];
+        let mut clock = AccountInfo::new(
+            &clock_key,
+            false,
+            false,
+            &mut clock_lamports,
+            &mut clock_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        Clock {
+            unix_timestamp: 500,
+            ..Clock::default()
+        }
+        .to_account_info(&mut clock)
+        .unwrap();
+        let rent_key = sysvar::rent::id();
+        let mut rent_lamports = 0;
+        let mut rent_data = vec![0; Rent::size_of()];
+        let mut rent = AccountInfo::new(
+            &rent_key,
+            false,
+            false,
+            &mut rent_lamports,
+            &mut rent_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        Rent::default().to_account_info(&mut rent).unwrap();
+        let instructions_key = sysvar::instructions::id();
+        let mut instructions_lamports = 0;
+        let mut instructions_data = top_level_instructions_data(&program_id);
+        let instructions = AccountInfo::new(
+            &instructions_key,
+            false,
+            false,
+            &mut instructions_lamports,
+            &mut instructions_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+
+        // Leaving and joining again in one transaction, the closed entry keeps its data
+        let leave_accounts = vec![
+            config.clone(),
+            account.clone(),
+            player.clone(),
+            entry.clone(),
+            vault.clone(),
+            clock.clone(),
+            instructions.clone(),
+        ];
+        let leave_data = RaceInstruction::LeaveRace.try_to_vec().unwrap();
+        process_instruction(&program_id, &leave_accounts, &leave_data).unwrap();
+        assert_eq!(entry.lamports(), 0);
+        assert_eq!(player.lamports(), entry_rent + 10);
+
+        let join_accounts = vec![
+            config,
+            account,
+            player,
+            entry,
+            vault,
+            system_program,
+            clock,
+            rent,
+            instructions,
+        ];
+        let join_data = RaceInstruction::JoinRace(JoinRaceArgs { slot: 1 })
+            .try_to_vec()
+            .unwrap();
+        INVOKED.with(|invoked| invoked.borrow_mut().clear());
+        process_instruction(&program_id, &join_accounts, &join_data).unwrap();
+        INVOKED.with(|invoked| {
+            assert!(invoked
+                .borrow()
+                .contains(&system_instruction::transfer(&player_key, &entry_key, entry_rent)));
+        });
+        let race_account = RaceAccount::from_account_info(&join_accounts[1]).unwrap();
+        assert_eq!(race_account.player_count(), 1);
+        assert!(!race_account.is_slot_taken(0));
+        assert!(race_account.is_slot_taken(1));
+        let entry = RaceEntry::from_account_info(&program_id, &join_accounts[1], &join_accounts[3]).unwrap();
+        assert_eq!(entry.slot, 1);
+        assert_eq!(entry.fee_paid, 10);
+    }
+
+    #[test]
+    fn test_migrate_race() {
+        let program_id = Pubkey::new_unique();
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let player_key = Pubkey::new_unique();
+        let mut data = vec![0; LEGACY_PLAYERS_OFFSET + LEGACY_MAX_PLAYERS * size_of::<LegacyPlayer>()];
+        // Version 1 accounts start with their version and keep the players in a table
+        data[0] = 1;
+        data[4] = 4;
+        data[5] = 1;
+        let legacy_player = LegacyPlayer {
+            address: player_key.to_bytes(),
+            fee_paid: 10,
+            taken: 1,
+            refunded: 0,
+            _padding: [0; 6],
+        };
+        let offset = LEGACY_PLAYERS_OFFSET + 3 * size_of::<LegacyPlayer>();
+        data[offset..offset + size_of::<LegacyPlayer>()].copy_from_slice(bytemuck::bytes_of(&legacy_player));
+        let account = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        assert_eq!(
+            RaceAccount::from_account_info(&account).err(),
+            Some(RaceError::RaceNeedsMigration.into())
+        );
+
+        let payer_key = Pubkey::new_unique();
+        let mut payer_lamports = 1_000_000_000;
+        let mut payer_data = vec![];
+        let payer = AccountInfo::new(
+            &payer_key,
+            true,
+            true,
+            &mut payer_lamports,
+            &mut payer_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let system_program_key = system_program::id();
+        let mut system_program_lamports = 0;
+        let mut system_program_data = vec![];
+        let system_program = AccountInfo::new(
+            &system_program_key,
+            false,
+            false,
+            &mut system_program_lamports,
+            &mut system_program_data,
+            &program_id,
+            true,
+            Epoch::default(),
+        );
+        let rent_key = sysvar::rent::id();
+        let mut rent_lamports = 0;
+        let mut rent_data = vec![0; Rent::size_of()];
+        let mut rent = AccountInfo::new(
+            &rent_key,
+            false,
+            false,
+            &mut rent_lamports,
+            &mut rent_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        Rent::default().to_account_info(&mut rent).unwrap();
+
+        let (entry_key, _) = find_race_entry_address(&program_id, &key, &player_key);
+        let mut entry_lamports = 0;
+        let mut entry_data = vec![0; RACE_ENTRY_LEN];
+        let entry = AccountInfo::new(
+            &entry_key,
+            false,
+            true,
+            &mut entry_lamports,
+            &mut entry_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+
+        let (config_key, _) = find_config_address(&program_id);
+        let mut config_lamports = 0;
+        let mut config_data = vec![0; PROGRAM_CONFIG_LEN];
+        {
+            let config = bytemuck::from_bytes_mut::<ProgramConfig>(&mut config_data[..]);
+            config.key = Key::ProgramConfig as u8;
+            config.version = PROGRAM_CONFIG_VERSION;
+            config.set_protocol_fee_bps(500).unwrap();
+        }
+        let config = AccountInfo::new(
+            &config_key,
+            false,
+            false,
+            &mut config_lamports,
+            &mut config_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+
+        let accounts = vec![config, account, payer, system_program, rent, entry];
+        let instruction_data = RaceInstruction::MigrateRace.try_to_vec().unwrap();
+        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+        {
+            let race_account = RaceAccount::from_account_info(&accounts[1]).unwrap();
+            assert_eq!(race_account.key, Key::RaceAccount as u8);
+            assert_eq!(race_account.version, RACE_ACCOUNT_VERSION);
+            assert_eq!(race_account.player_count(), 1);
+            assert_eq!(race_account.protocol_fee_bps, 500);
+            assert!(race_account.is_slot_taken(3));
+            assert!(!race_account.is_slot_taken(0));
+            let entry = RaceEntry::from_account_info(&program_id, &accounts[1], &accounts[5]).unwrap();
+            assert_eq!(entry.player(), player_key);
+            assert_eq!(entry.slot, 3);
+            assert_eq!(entry.fee_paid, 10);
+        }
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction_data),
+            Err(RaceError::RaceAlreadyMigrated.into())
+        );
+    }
+
+    #[test]
+    fn test_cpi_allowlist() {
+        let program_id = Pubkey::new_unique();
+        let (config_key, _) = find_config_address(&program_id);
+        let mut config_lamports = 0;
+        let mut config_data = vec![0; PROGRAM_CONFIG_LEN];
+        let admin_key = Pubkey::new_unique();
+        {
+            let config = bytemuck::from_bytes_mut::<ProgramConfig>(&mut config_data[..]);
+            config.key = Key::ProgramConfig as u8;
+            config.version = PROGRAM_CONFIG_VERSION;
+            config.admin = admin_key.to_bytes();
+        }
+        let config = AccountInfo::new(
+            &config_key,
+            false,
+            true,
+            &mut config_lamports,
+            &mut config_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let mut admin_lamports = 0;
+        let mut admin_data = vec![];
+        let admin = AccountInfo::new(
+            &admin_key,
+            true,
+            false,
+            &mut admin_lamports,
+            &mut admin_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+
+        let caller_key = Pubkey::new_unique();
+        let instruction_data = RaceInstruction::SetAllowedPrograms(SetAllowedProgramsArgs {
+            allowed_programs: vec![Pubkey::new_unique(); MAX_ALLOWED_PROGRAMS + 1],
+        })
+        .try_to_vec()
+        .unwrap();
+        let accounts = vec![config.clone(), admin.clone()];
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction_data),
+            Err(RaceError::TooManyAllowedPrograms.into())
+        );
+        let mut unsigned_admin = admin.clone();
+        unsigned_admin.is_signer = false;
+        let instruction_data = RaceInstruction::SetAllowedPrograms(SetAllowedProgramsArgs {
+            allowed_programs: vec![caller_key],
+        })
+        .try_to_vec()
+        .unwrap();
+        assert_eq!(
+            process_instruction(&program_id, &[config.clone(), unsigned_admin], &instruction_data),
+            Err(RaceError::AdminIncorrect.into())
+        );
+        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+
+        let other_key = Pubkey::new_unique();
+        let mut other_lamports = 0;
+        let mut other_data = vec![];
+        let other = AccountInfo::new(
+            &other_key,
+            false,
+            false,
+            &mut other_lamports,
+            &mut other_data,
+            &program_id,
+            true,
+            Epoch::default(),
+        );
+        let mut caller_lamports = 0;
+        let mut caller_data = vec![];
+        let caller = AccountInfo::new(
+            &caller_key,
+            false,
+            false,
+            &mut caller_lamports,
+            &mut caller_data,
+            &program_id,
+            true,
+            Epoch::default(),
+        );
+        let (cpi_authority_key, _) = find_cpi_authority_address(&caller_key);
+        let mut cpi_authority_lamports = 0;
+        let mut cpi_authority_data = vec![];
+        let cpi_authority = AccountInfo::new(
+            &cpi_authority_key,
+            false,
+            false,
+            &mut cpi_authority_lamports,
+            &mut cpi_authority_data,
+            &caller_key,
+            false,
+            Epoch::default(),
+        );
+
+        let accounts = vec![other, cpi_authority.clone(), config.clone()];
+        assert_eq!(
+            assert_cpi_caller(&program_id, &accounts).err(),
+            Some(RaceError::CallerNotAllowed.into())
+        );
+        let accounts = vec![caller.clone(), cpi_authority.clone(), config.clone()];
+        assert_eq!(
+            assert_cpi_caller(&program_id, &accounts).err(),
+            Some(RaceError::InvalidCpiAuthority.into())
+        );
+        let mut signed_cpi_authority = cpi_authority;
+        signed_cpi_authority.is_signer = true;
+
+        // A program off the allowlist invoking plain JoinRace or LeaveRace, signing for a
+        // player PDA of its own, is not the top-level instruction
+        let instructions_key = sysvar::instructions::id();
+        let mut invoked_lamports = 0;
+        let mut invoked_data = top_level_instructions_data(&other_key);
+        let invoked_instructions = AccountInfo::new(
+            &instructions_key,
+            false,
+            false,
+            &mut invoked_lamports,
+            &mut invoked_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let join_data = RaceInstruction::JoinRace(JoinRaceArgs { slot: 0 })
+            .try_to_vec()
+            .unwrap();
+        let leave_data = RaceInstruction::LeaveRace.try_to_vec().unwrap();
+        let accounts = vec![config.clone(), invoked_instructions];
+        for instruction_data in [join_data, leave_data] {
+            assert_eq!(
+                process_instruction(&program_id, &accounts, &instruction_data),
+                Err(RaceError::CallerNotAllowed.into())
+            );
+        }
+        let mut top_level_lamports = 0;
+        let mut top_level_data = top_level_instructions_data(&program_id);
+        let top_level_instructions = AccountInfo::new(
+            &instructions_key,
+            false,
+            false,
+            &mut top_level_lamports,
+            &mut top_level_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let accounts = vec![config.clone(), top_level_instructions];
+        let race_accounts = assert_top_level_caller(&program_id, &accounts).unwrap();
+        assert_eq!(race_accounts.len(), 1);
+        assert_eq!(*race_accounts[0].key, config_key);
+
+        let accounts = vec![caller, signed_cpi_authority, config, admin];
+        let race_accounts = assert_cpi_caller(&program_id, &accounts).unwrap();
+        assert_eq!(*race_accounts[0].key, config_key);
+        assert_eq!(*race_accounts[1].key, admin_key);
+    }
+
+    #[test]
+    fn test_paused() {
+        let program_id = Pubkey::new_unique();
+        let admin_key = Pubkey::new_unique();
+        let config = new_config(&program_id, |config| config.admin = admin_key.to_bytes());
+        let admin = new_account(admin_key, true, false, 0, vec![], &program_id);
+        let account = new_race(&program_id, Pubkey::new_unique(), |race_account| {
+            race_account.authority = admin_key.to_bytes();
+            race_account.set_status(RaceStatus::Open);
+        });
+
+        let pause_data = RaceInstruction::SetPaused(SetPausedArgs { paused: true })
+            .try_to_vec()
+            .unwrap();
+        process_instruction(&program_id, &[config.clone(), admin.clone()], &pause_data).unwrap();
+
+        let accounts = vec![config.clone(), account, admin.clone()];
+        let instruction_data = RaceInstruction::SetAuthority(SetAuthorityArgs {
+            new_authority: Pubkey::new_unique(),
+        })
+        .try_to_vec()
+        .unwrap();
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction_data),
+            Err(RaceError::ProgramPaused.into())
+        );
+
+        // Refund paths stay available so players can get their entry fees back
+        let cancel_data = RaceInstruction::CancelRace.try_to_vec().unwrap();
+        process_instruction(&program_id, &accounts, &cancel_data).unwrap();
+        assert_eq!(
+            RaceAccount::from_account_info(&accounts[1]).unwrap().status().unwrap(),
+            RaceStatus::Cancelled
+        );
+
+        let key = Pubkey::new_unique();
+        let player_key = Pubkey::new_unique();
+        let leave_race = new_race(&program_id, key, |race_account| {
+            race_account.set_status(RaceStatus::Open);
+            race_account.date = 1_000;
+            race_account.max_players = 2;
+            race_account.min_players = 1;
+            race_account.player_count = 1;
+            race_account.set_slot_taken(0, true);
+            race_account.prize_pool = 10;
+        });
+        let (entry_key, bump_seed) = find_race_entry_address(&program_id, &key, &player_key);
+        let mut entry_data = vec![0; RACE_ENTRY_LEN];
+        {
+            let entry = bytemuck::from_bytes_mut::<RaceEntry>(&mut entry_data[..]);
+            entry.key = Key::RaceEntry as u8;
+            entry.bump_seed = bump_seed;
+            entry.race = key.to_bytes();
+            entry.player = player_key.to_bytes();
+            entry.fee_paid = 10;
+        }
+        let (vault_key, _) = find_vault_address(&program_id, &key);
+        let clock = new_clock(500);
+        let leave_accounts = vec![
+            config.clone(),
+            leave_race,
+            new_account(player_key, true, true, 0, vec![], &program_id),
+            new_account(entry_key, false, true, 0, entry_data, &program_id),
+            new_account(vault_key, false, true, 10, vec![], &program_id),
+            clock.clone(),
+            new_account(
+                sysvar::instructions::id(),
+                false,
+                false,
+                0,
+                top_level_instructions_data(&program_id),
+                &sysvar::id(),
+            ),
+        ];
+        let leave_data = RaceInstruction::LeaveRace.try_to_vec().unwrap();
+        process_instruction(&program_id, &leave_accounts, &leave_data).unwrap();
+        assert_eq!(leave_accounts[2].lamports(), 10);
+
+        // CheckRaceStart cancels the race the player left, but starts none until resumed
+        set_clock(&clock, 1_000);
+        let start_data = RaceInstruction::CheckRaceStart.try_to_vec().unwrap();
+        let cancel_accounts = vec![config.clone(), leave_accounts[1].clone(), clock.clone()];
+        process_instruction(&program_id, &cancel_accounts, &start_data).unwrap();
+        assert_eq!(
+            RaceAccount::from_account_info(&cancel_accounts[1]).unwrap().status().unwrap(),
+            RaceStatus::Cancelled
+        );
+        let start_race = new_race(&program_id, Pubkey::new_unique(), |race_account| {
+            race_account.set_status(RaceStatus::Full);
+            race_account.date = 1_000;
+            race_account.max_players = 1;
+            race_account.min_players = 1;
+            race_account.player_count = 1;
+        });
+        let start_accounts = vec![config.clone(), start_race, clock];
+        assert_eq!(
+            process_instruction(&program_id, &start_accounts, &start_data),
+            Err(RaceError::ProgramPaused.into())
+        );
+
+        let resume_data = RaceInstruction::SetPaused(SetPausedArgs { paused: false })
+            .try_to_vec()
+            .unwrap();
+        process_instruction(&program_id, &[config, admin], &resume_data).unwrap();
+        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+        process_instruction(&program_id, &start_accounts, &start_data).unwrap();
+        assert_eq!(
+            RaceAccount::from_account_info(&start_accounts[1]).unwrap().status().unwrap(),
+            RaceStatus::Running
+        );
+    }
+
+    #[test]
+    fn test_config_version() {
+        let program_id = Pubkey::new_unique();
+        let admin_key = Pubkey::new_unique();
+        let config = new_config(&program_id, |config| config.admin = admin_key.to_bytes());
+        let admin = new_account(admin_key, true, false, 0, vec![], &program_id);
+
+        let accounts = vec![config.clone(), admin];
+        let pause_data = RaceInstruction::SetPaused(SetPausedArgs { paused: true })
+            .try_to_vec()
+            .unwrap();
+        // Configs never written by InitializeConfig, or written by a newer program, are refused
+        for version in [0, PROGRAM_CONFIG_VERSION + 1] {
+            ProgramConfig::from_account_info_unchecked(&config).unwrap().version = version;
+            assert_eq!(
+                process_instruction(&program_id, &accounts, &pause_data),
+                Err(RaceError::UnsupportedConfigVersion.into())
+            );
+        }
+        ProgramConfig::from_account_info_unchecked(&config).unwrap().version = PROGRAM_CONFIG_VERSION;
+        process_instruction(&program_id, &accounts, &pause_data).unwrap();
+        assert!(ProgramConfig::from_account_info(&program_id, &config).unwrap().is_paused());
+    }
+
+    #[test]
+    fn test_check_race_start() {
+        let program_id = Pubkey::new_unique();
+        let config = new_config(&program_id, |_| ());
+        let account = new_race(&program_id, Pubkey::new_unique(), |race_account| {
+            race_account.set_status(RaceStatus::Open);
+            race_account.date = 1_000;
+            race_account.max_players = 4;
+            race_account.min_players = 2;
+            race_account.player_count = 1;
+        });
+
+        let accounts = vec![config, account, new_clock(999)];
+        let instruction_data = RaceInstruction::CheckRaceStart.try_to_vec().unwrap();
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction_data),
+            Err(RaceError::RaceNotStarted.into())
+        );
+
+        set_clock(&accounts[2], 1_000);
+        RaceAccount::from_account_info(&accounts[1]).unwrap().player_count = 2;
+        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+        assert_eq!(
+            RaceAccount::from_account_info(&accounts[1]).unwrap().status().unwrap(),
+            RaceStatus::Running
+        );
+
+        // One player short of the minimum
+        {
+            let mut race_account = RaceAccount::from_account_info(&accounts[1]).unwrap();
+            race_account.set_status(RaceStatus::Open);
+            race_account.player_count = 1;
+        }
+        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+        assert_eq!(
+            RaceAccount::from_account_info(&accounts[1]).unwrap().status().unwrap(),
+            RaceStatus::Cancelled
+        );
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction_data),
+            Err(RaceError::InvalidStatusTransition.into())
+        );
+    }
+
+    #[test]
+    fn test_advance_race() {
+        let program_id = Pubkey::new_unique();
+        let config = new_config(&program_id, |config| config.crank_reward = 5);
+        let key = Pubkey::new_unique();
+        let account = new_race(&program_id, key, |race_account| {
+            race_account.set_status(RaceStatus::Open);
+            race_account.date = 1_000;
+            race_account.lock_period = 100;
+            race_account.end_date = 2_000;
+            race_account.max_players = 4;
+            race_account.player_count = 1;
+            race_account.prize_pool = 10;
+        });
+        // Rent exemption and the prize pool are kept, 7 lamports fund the crank rewards
+        let (vault_key, _) = find_vault_address(&program_id, &key);
+        let vault_lamports = Rent::default().minimum_balance(0) + 10 + 7;
+        let vault = new_account(vault_key, false, true, vault_lamports, vec![], &program_id);
+        let caller = new_account(Pubkey::new_unique(), true, true, 0, vec![], &program_id);
+        let mut rent = new_account(
+            sysvar::rent::id(),
+            false,
+            false,
+            0,
+            vec![0; Rent::size_of()],
+            &sysvar::id(),
+        );
+        Rent::default().to_account_info(&mut rent).unwrap();
+
+        let accounts = vec![config, account, vault, caller, new_clock(899), rent];
+        let instruction_data = RaceInstruction::AdvanceRace.try_to_vec().unwrap();
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction_data),
+            Err(RaceError::RaceNotReady.into())
+        );
+
+        set_clock(&accounts[4], 900);
+        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+        assert_eq!(
+            RaceAccount::from_account_info(&accounts[1]).unwrap().status().unwrap(),
+            RaceStatus::Closed
+        );
+        assert_eq!(accounts[3].lamports(), 5);
+
+        // Runs and finishes in one call, the reward is capped by the remaining surplus
+        set_clock(&accounts[4], 2_000);
+        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+        assert_eq!(
+            RaceAccount::from_account_info(&accounts[1]).unwrap().status().unwrap(),
+            RaceStatus::AwaitingResult
+        );
+        assert_eq!(accounts[3].lamports(), 7);
+        assert_eq!(accounts[2].lamports(), Rent::default().minimum_balance(0) + 10);
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction_data),
+            Err(RaceError::RaceNotReady.into())
+        );
+    }
+
+    #[test]
+    fn test_submit_result() {
+        let program_id = Pubkey::new_unique();
+        let result_signer = Pubkey::new_unique();
+        let treasury_key = Pubkey::new_unique();
+        let config = new_config(&program_id, |config| {
+            config.set_protocol_fee_bps(1_000).unwrap();
+            config.treasury = treasury_key.to_bytes();
+            config.result_signer = result_signer.to_bytes();
+        });
+        let key = Pubkey::new_unique();
+        let account = new_race(&program_id, key, |race_account| {
+            race_account.set_status(RaceStatus::AwaitingResult);
+            race_account.end_date = 500;
+            race_account.max_players = 2;
+            race_account.player_count = 1;
+            race_account.set_slot_taken(0, true);
+            race_account.prize_pool = 10;
+            race_account.set_payout_table(&[PAYOUT_BPS]).unwrap();
+            race_account.protocol_fee_bps = 1_000;
+        });
+        let (vault_key, _) = find_vault_address(&program_id, &key);
+        let vault = new_account(vault_key, false, true, 10, vec![], &program_id);
+        let treasury = new_account(treasury_key, false, true, 0, vec![], &program_id);
+        let player_key = Pubkey::new_unique();
+        let (entry_key, bump_seed) = find_race_entry_address(&program_id, &key, &player_key);
+        let mut entry_data = vec![0; RACE_ENTRY_LEN];
+        {
+            let entry = bytemuck::from_bytes_mut::<RaceEntry>(&mut entry_data[..]);
+            entry.key = Key::RaceEntry as u8;
+            entry.bump_seed = bump_seed;
+            entry.race = key.to_bytes();
+            entry.player = player_key.to_bytes();
+            entry.fee_paid = 10;
+        }
+        let entry = new_account(entry_key, false, false, 0, entry_data, &program_id);
+        let player = new_account(player_key, false, true, 0, vec![], &program_id);
+
+        // The instructions sysvar of a transaction with the Ed25519 instruction before SubmitResult
+        let result_hash = [7; 32];
+        let submit = submit_result(
+            &program_id,
+            &key,
+            &treasury_key,
+            None,
+            result_hash,
+            vec![0],
+            &[player_key],
+        );
+        let instructions = |signer: &Pubkey| {
+            let message = result_message(&key, &result_hash, &[0]);
+            let verify = verify_ed25519_signature(signer, &[1; 64], &message);
+            let mut data = Message::new(&[verify, submit.clone()], None).serialize_instructions();
+            data.extend_from_slice(&1u16.to_le_bytes());
+            new_account(sysvar::instructions::id(), false, false, 0, data, &sysvar::id())
+        };
+        let mut accounts = vec![
+            config,
+            account,
+            vault,
+            new_clock(500),
+            instructions(&Pubkey::new_unique()),
+            treasury,
+            entry,
+            player,
+        ];
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &submit.data),
+            Err(RaceError::InvalidResultAttestation.into())
+        );
+
+        accounts[4] = instructions(&result_signer);
+        process_instruction(&program_id, &accounts, &submit.data).unwrap();
+        assert_eq!(accounts[5].lamports(), 1);
+        assert_eq!(accounts[7].lamports(), 9);
+        {
+            let race_account = RaceAccount::from_account_info(&accounts[1]).unwrap();
+            assert_eq!(race_account.status().unwrap(), RaceStatus::Settled);
+            assert_eq!(race_account.results(), &[0]);
+            assert_eq!(race_account.prize_pool, 0);
+        }
+    }
+
+    #[test]
+    fn test_settle_empty_race() {
+        let program_id = Pubkey::new_unique();
+        let treasury_key = Pubkey::new_unique();
+        let config = new_config(&program_id, |config| {
+            config.set_protocol_fee_bps(1_000).unwrap();
+            config.treasury = treasury_key.to_bytes();
+        });
+        let key = Pubkey::new_unique();
+        let authority_key = Pubkey::new_unique();
+        // Nobody joined a race starting without a minimum, its sponsors funded the prize pool
+        let account = new_race(&program_id, key, |race_account| {
+            race_account.set_status(RaceStatus::AwaitingResult);
+            race_account.authority = authority_key.to_bytes();
+            race_account.end_date = 500;
+            race_account.max_players = 2;
+            race_account.prize_pool = 10;
+            race_account.set_payout_table(&[PAYOUT_BPS]).unwrap();
+        });
+        let (vault_key, _) = find_vault_address(&program_id, &key);
+        let accounts = vec![
+            config,
+            account,
+            new_account(authority_key, true, false, 0, vec![], &program_id),
+            new_account(vault_key, false, true, 10, vec![], &program_id),
+            new_clock(500),
+            new_account(treasury_key, false, true, 0, vec![], &program_id),
+        ];
+        let instruction_data = RaceInstruction::SettleRace(SettleRaceArgs { results: vec![] })
+            .try_to_vec()
+            .unwrap();
+        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+        assert_eq!(accounts[3].lamports(), 0);
+        assert_eq!(accounts[5].lamports(), 10);
+        let race_account = RaceAccount::from_account_info(&accounts[1]).unwrap();
+        assert_eq!(race_account.status().unwrap(), RaceStatus::Settled);
+        assert_eq!(race_account.prize_pool, 0);
+    }
+
+    #[test]
+    fn test_initialize_config() {
+        program_stubs::set_syscall_stubs(Box::new(RecordingStubs));
+        let program_id = Pubkey::new_unique();
+        let payer_key = Pubkey::new_unique();
+        let upgrade_authority = Pubkey::new_unique();
+        let instruction = initialize_config(
+            &program_id,
+            &payer_key,
+            &payer_key,
+            &upgrade_authority,
+            InitializeConfigArgs {
+                protocol_fee_bps: 0,
+                treasury: Pubkey::new_unique(),
+                max_entry_fee: 0,
+                max_players: 0,
+                crank_reward: 0,
+                result_signer: None,
+                allowed_programs: vec![],
+            },
+        );
+        // Bincode encoded UpgradeableLoaderState::ProgramData of the deployed program
+        let program_data = |key: Pubkey, authority: Option<&Pubkey>| {
+            let mut data = 3u32.to_le_bytes().to_vec();
+            data.extend_from_slice(&0u64.to_le_bytes());
+            match authority {
+                Some(authority) => {
+                    data.push(1);
+                    data.extend_from_slice(authority.as_ref());
+                }
+                None => data.extend_from_slice(&[0; 33]),
+            }
+            new_account(key, false, false, 0, data, &bpf_loader_upgradeable::id())
+        };
+        let program_data_key = instruction.accounts[5].pubkey;
+        let mut rent = new_account(
+            sysvar::rent::id(),
+            false,
+            false,
+            0,
+            vec![0; Rent::size_of()],
+            &sysvar::id(),
+        );
+        Rent::default().to_account_info(&mut rent).unwrap();
+        let payer = new_account(payer_key, true, true, 0, vec![], &system_program::id());
+        let mut accounts = vec![
+            new_account(
+                instruction.accounts[0].pubkey,
+                false,
+                true,
+                0,
+                vec![],
+                &system_program::id(),
+            ),
+            payer.clone(),
+            payer.clone(),
+            new_account(system_program::id(), false, false, 0, vec![], &Pubkey::default()),
+            rent,
+            program_data(Pubkey::new_unique(), Some(&upgrade_authority)),
+            new_account(upgrade_authority, true, false, 0, vec![], &system_program::id()),
+        ];
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction.data),
+            Err(RaceError::InvalidProgramData.into())
+        );
+
+        accounts[5] = program_data(program_data_key, None);
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction.data),
+            Err(RaceError::UpgradeAuthorityIncorrect.into())
+        );
+        // The payer signing in place of the upgrade authority is not enough
+        accounts[5] = program_data(program_data_key, Some(&upgrade_authority));
+        accounts[6] = payer;
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction.data),
+            Err(RaceError::UpgradeAuthorityIncorrect.into())
+        );
+
+        // The stubbed system program does not allocate, creating the config is as far as it gets
+        accounts[6] = new_account(upgrade_authority, true, false, 0, vec![], &system_program::id());
+        INVOKED.with(|invoked| invoked.borrow_mut().clear());
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction.data),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+        let allocate = system_instruction::allocate(accounts[0].key, PROGRAM_CONFIG_LEN as u64);
+        INVOKED.with(|invoked| assert!(invoked.borrow().contains(&allocate)));
+    }
+
+    /// SPL token account holding `fee_mint` for `owner`
+    fn new_token_account(key: Pubkey, fee_mint: &Pubkey, owner: &Pubkey) -> AccountInfo<'static> {
+        let mut data = vec![0; spl_token::state::Account::LEN];
+        spl_token::state::Account {
+            mint: *fee_mint,
+            owner: *owner,
+            state: spl_token::state::AccountState::Initialized,
+            ..spl_token::state::Account::default()
+        }
+        .pack_into_slice(&mut data);
+        new_account(key, false, true, 0, data, &spl_token::id())
+    }
+
+    #[test]
+    fn test_fee_mint() {
+        program_stubs::set_syscall_stubs(Box::new(RecordingStubs));
+        let program_id = Pubkey::new_unique();
+        let fee_mint = Pubkey::new_unique();
+        let treasury_key = Pubkey::new_unique();
+        let config = new_config(&program_id, |config| {
+            config.set_protocol_fee_bps(1_000).unwrap();
+            config.treasury = treasury_key.to_bytes();
+        });
+        let key = Pubkey::new_unique();
+        let authority_key = Pubkey::new_unique();
+        let account = new_race(&program_id, key, |race_account| {
+            race_account.set_status(RaceStatus::Open);
+            race_account.authority = authority_key.to_bytes();
+            race_account.fee_mint = fee_mint.to_bytes();
+            race_account.end_date = 500;
+            race_account.max_players = 2;
+            race_account.set_payout_table(&[PAYOUT_BPS]).unwrap();
+            race_account.protocol_fee_bps = 1_000;
+        });
+        let (vault_key, _) = find_vault_address(&program_id, &key);
+        let vault = new_account(vault_key, false, true, 0, vec![], &program_id);
+        let vault_token_key = get_associated_token_address(&vault_key, &fee_mint);
+        let vault_token = new_token_account(vault_token_key, &fee_mint, &vault_key);
+        let token_program = new_account(spl_token::id(), false, false, 0, vec![], &Pubkey::default());
+
+        // Sponsoring moves the tokens from the sponsor's account into the vault's
+        let sponsor_key = Pubkey::new_unique();
+        let sponsor_token_key = Pubkey::new_unique();
+        let mut accounts = vec![
+            config.clone(),
+            account.clone(),
+            new_account(sponsor_key, true, true, 0, vec![], &system_program::id()),
+            vault.clone(),
+            new_account(system_program::id(), false, false, 0, vec![], &Pubkey::default()),
+            new_token_account(sponsor_token_key, &fee_mint, &sponsor_key),
+            new_token_account(Pubkey::new_unique(), &fee_mint, &vault_key),
+            token_program.clone(),
+        ];
+        let instruction_data = RaceInstruction::SponsorRace(SponsorRaceArgs { amount: 100 })
+            .try_to_vec()
+            .unwrap();
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction_data),
+            Err(RaceError::InvalidVaultTokenAccount.into())
+        );
+        accounts[6] = vault_token.clone();
+        INVOKED.with(|invoked| invoked.borrow_mut().clear());
+        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+        let transfer = INVOKED.with(|invoked| invoked.borrow_mut().pop().unwrap());
+        assert_eq!(transfer.program_id, spl_token::id());
+        assert_eq!(
+            transfer.accounts,
+            vec![
+                AccountMeta::new(sponsor_token_key, false),
+                AccountMeta::new(vault_token_key, false),
+                AccountMeta::new_readonly(sponsor_key, true),
+            ]
+        );
+        assert_eq!(
+            transfer.data,
+            spl_token::instruction::TokenInstruction::Transfer { amount: 100 }.pack()
+        );
+        assert_eq!(RaceAccount::from_account_info(&account).unwrap().prize_pool, 100);
+
+        // Settling pays out of the vault's token account into token accounts of the fee mint
+        // owned by the treasury and the winners
+        {
+            let mut race_account = RaceAccount::from_account_info(&account).unwrap();
+            race_account.set_status(RaceStatus::AwaitingResult);
+            race_account.player_count = 1;
+            race_account.set_slot_taken(0, true);
+        }
+        let player_key = Pubkey::new_unique();
+        let (entry_key, bump_seed) = find_race_entry_address(&program_id, &key, &player_key);
+        let mut entry_data = vec![0; RACE_ENTRY_LEN];
+        {
+            let entry = bytemuck::from_bytes_mut::<RaceEntry>(&mut entry_data[..]);
+            entry.key = Key::RaceEntry as u8;
+            entry.bump_seed = bump_seed;
+            entry.race = key.to_bytes();
+            entry.player = player_key.to_bytes();
+        }
+        let treasury_token_key = Pubkey::new_unique();
+        let player_token_key = Pubkey::new_unique();
+        let mut accounts = vec![
+            config,
+            account.clone(),
+            new_account(authority_key, true, false, 0, vec![], &system_program::id()),
+            vault,
+            new_clock(500),
+            new_token_account(Pubkey::new_unique(), &fee_mint, &vault_key),
+            token_program,
+            new_token_account(treasury_token_key, &fee_mint, &treasury_key),
+            new_account(entry_key, false, false, 0, entry_data, &program_id),
+            new_token_account(player_token_key, &fee_mint, &player_key),
+        ];
+        let instruction_data = RaceInstruction::SettleRace(SettleRaceArgs { results: vec![0] })
+            .try_to_vec()
+            .unwrap();
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction_data),
+            Err(RaceError::InvalidVaultTokenAccount.into())
+        );
+        accounts[5] = vault_token;
+        accounts[9] = new_token_account(player_token_key, &Pubkey::new_unique(), &player_key);
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction_data),
+            Err(RaceError::InvalidPayoutAccount.into())
+        );
+        accounts[9] = new_token_account(player_token_key, &fee_mint, &Pubkey::new_unique());
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction_data),
+            Err(RaceError::InvalidPayoutAccount.into())
+        );
+        accounts[9] = new_token_account(player_token_key, &fee_mint, &player_key);
+        INVOKED.with(|invoked| invoked.borrow_mut().clear());
+        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+        let transfers = INVOKED.with(|invoked| invoked.borrow_mut().split_off(0));
+        let expected = [(treasury_token_key, 10), (player_token_key, 90)]
+            .iter()
+            .map(|(destination, amount)| {
+                spl_token::instruction::transfer(
+                    &spl_token::id(),
+                    &vault_token_key,
+                    destination,
+                    &vault_key,
+                    &[],
+                    *amount,
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(transfers, expected);
+    }
+}
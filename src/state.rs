@@ -0,0 +1,595 @@
+//! State transition types
+
+use crate::{
+    error::RaceError,
+    utils::{assert_race_entry, assert_valid_payout_table},
+};
+use borsh::{BorshDeserialize, BorshSerialize};
+use bytemuck::{Pod, Zeroable};
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive as _;
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, msg, program_error::ProgramError,
+    pubkey::Pubkey,
+};
+use std::{cell::RefMut, mem::size_of};
+
+/// Prefix used in race account seeds
+pub const PREFIX: &str = "race";
+
+/// Seed of the vault holding a race's entry fees and sponsor funds
+pub const VAULT: &str = "vault";
+
+/// Seed of the program config
+pub const CONFIG: &str = "config";
+
+/// Seed of the PDA an invoking program signs CPIs with, derived from the invoking program id
+pub const CPI_AUTHORITY: &str = "race-cpi";
+
+/// Number of programs the config can allow to enter races through CPI
+pub const MAX_ALLOWED_PROGRAMS: usize = 8;
+
+pub const MAX_NAME_LENGTH: usize = 32;
+
+pub const MAX_LOCATION_LENGTH: usize = 32;
+
+pub const MAX_GAME_URL_LENGTH: usize = 200;
+
+/// Shortest race in metres
+pub const MIN_DISTANCE: u16 = 800;
+
+/// Longest race in metres
+pub const MAX_DISTANCE: u16 = 5_000;
+
+/// Highest number of slots a race can have, slots are numbered by a u8
+pub const MAX_PLAYERS: usize = 255;
+
+/// Bytes of the bitmap of taken slots, one bit per slot
+pub const SLOT_BITMAP_LEN: usize = 32;
+
+/// Number of finishing places a payout table can reward
+pub const MAX_PAYOUT_PLACES: usize = 10;
+
+/// Basis points the payout table shares add up to, also the cap of the withdrawal fee
+pub const PAYOUT_BPS: u16 = 10_000;
+
+/// Layout version written to new and migrated race accounts
+pub const RACE_ACCOUNT_VERSION: u8 = 3;
+
+/// Size of new race accounts, migrated ones keep their larger size
+pub const RACE_ACCOUNT_LEN: usize = size_of::<RaceAccount>();
+
+/// Size of every race entry account
+pub const RACE_ENTRY_LEN: usize = size_of::<RaceEntry>();
+
+/// Layout version written to new program configs. Fields added later take the place of
+/// `ProgramConfig::_reserved`, which reads as zeros in configs of older versions.
+pub const PROGRAM_CONFIG_VERSION: u8 = 1;
+
+/// Size of the program config account
+pub const PROGRAM_CONFIG_LEN: usize = size_of::<ProgramConfig>();
+
+/// Start of the player table in the version 1 and 2 race layouts
+pub const LEGACY_PLAYERS_OFFSET: usize = 472;
+
+/// Number of slots in the player table of the version 1 and 2 race layouts
+pub const LEGACY_MAX_PLAYERS: usize = 32;
+
+/// Account type, stored in the first byte of every account the program owns
+#[derive(BorshSerialize, BorshDeserialize, FromPrimitive, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Key {
+    Uninitialized,
+    /// Race account written before the key was added, where the first byte held version 1
+    RaceAccountV1,
+    RaceAccount,
+    RaceEntry,
+    ProgramConfig,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, FromPrimitive, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum RaceStatus {
+    /// Being set up, not visible to players yet
+    Draft,
+    /// Accepting players
+    Open,
+    /// Every slot is taken
+    Full,
+    Running,
+    /// Race is over, waiting for settlement
+    AwaitingResult,
+    /// Results recorded and prize pool paid out
+    Settled,
+    Cancelled,
+    /// Registration closed at the lock time, waiting for the race date
+    Closed,
+}
+
+impl RaceStatus {
+    /// Whether the race authority may move a race from this status to `next`.
+    /// Running is only reached through CheckRaceStart or AdvanceRace at the race date,
+    /// Settled through SettleRace and Cancelled through CancelRace.
+    pub fn can_transition_to(self, next: RaceStatus) -> bool {
+        use RaceStatus::*;
+        self == next
+            || matches!(
+                (self, next),
+                (Draft, Open)
+                    | (Open, Draft)
+                    | (Open, Closed)
+                    | (Full, Closed)
+                    | (Running, AwaitingResult)
+            )
+    }
+
+    /// Settled and cancelled races are final
+    pub fn is_closed(self) -> bool {
+        matches!(self, RaceStatus::Settled | RaceStatus::Cancelled)
+    }
+}
+
+/// Define the type of state stored in accounts.
+///
+/// The layout is fixed-size and read in place, strings and lists are stored in bounded
+/// arrays next to their length, and optional keys are all zeros when unset.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub struct RaceAccount {
+    pub key: u8,
+    pub status: u8,
+    pub level: u8,
+    pub r#type: u8,
+    /// Number of slots, players take slots `0..max_players`
+    pub max_players: u8,
+    pub player_count: u8,
+    pub name_len: u8,
+    pub location_len: u8,
+    pub game_url_len: u8,
+    pub results_len: u8,
+    pub payout_places: u8,
+    pub version: u8,
+    pub distance: u16,
+    /// Share of the entry fee in basis points kept in the prize pool when a player leaves
+    pub withdrawal_fee_bps: u16,
+    pub authority: [u8; 32],
+    /// Mint of the token fees and prizes are paid in, lamports when unset.
+    /// The vault's associated token account for it must exist before anything is paid in.
+    pub fee_mint: [u8; 32],
+    /// Key allowed to settle the race besides the authority
+    pub oracle: [u8; 32],
+    pub date: u64,
+    pub end_date: u64,
+    /// Seconds before `date` from which players can no longer join or leave
+    pub lock_period: u64,
+    /// Entry fee in lamports, or in base units of `fee_mint`
+    pub entry_fee: u64,
+    /// Entry fees collected plus sponsor contributions, held in the vault
+    pub prize_pool: u64,
+    pub name: [u8; MAX_NAME_LENGTH],
+    pub location: [u8; MAX_LOCATION_LENGTH],
+    pub game_url: [u8; MAX_GAME_URL_LENGTH],
+    /// Share of the prize pool in basis points for each finishing place
+    pub payout_table: [u16; MAX_PAYOUT_PLACES],
+    /// Slot of every player in finishing order, set once the race is settled
+    pub results: [u8; MAX_PLAYERS],
+    /// Players needed for the race to start, CheckRaceStart cancels it otherwise
+    pub min_players: u8,
+    /// Share of the prize pool paid to the treasury, the config's protocol fee when the race
+    /// was created so it cannot change once players paid in
+    pub protocol_fee_bps: u16,
+    pub _padding2: [u8; 2],
+    /// Bitmap of the taken slots, the players themselves are in their race entries
+    pub slots: [u8; SLOT_BITMAP_LEN],
+}
+
+impl RaceAccount {
+    /// Borrow the race account data, failing unless it holds a race on the current layout
+    pub fn from_account_info<'a>(a: &'a AccountInfo) -> Result<RefMut<'a, RaceAccount>, ProgramError> {
+        let race_account = RaceAccount::from_account_info_unchecked(a)?;
+        match Key::from_u8(race_account.key) {
+            Some(Key::RaceAccount) if race_account.version == RACE_ACCOUNT_VERSION => Ok(race_account),
+            Some(Key::RaceAccount) | Some(Key::RaceAccountV1) => Err(RaceError::RaceNeedsMigration.into()),
+            _ => Err(RaceError::DataTypeMismatch.into()),
+        }
+    }
+
+    /// Borrow the account data as a race without looking at the key, for new and migrated accounts
+    pub fn from_account_info_unchecked<'a>(
+        a: &'a AccountInfo,
+    ) -> Result<RefMut<'a, RaceAccount>, ProgramError> {
+        let data = a.data.borrow_mut();
+        if data.len() < RACE_ACCOUNT_LEN {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        bytemuck::try_from_bytes::<RaceAccount>(&data[..RACE_ACCOUNT_LEN])
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(RefMut::map(data, |data| {
+            bytemuck::from_bytes_mut(&mut data[..RACE_ACCOUNT_LEN])
+        }))
+    }
+
+    pub fn status(&self) -> Result<RaceStatus, ProgramError> {
+        RaceStatus::from_u8(self.status).ok_or(ProgramError::InvalidAccountData)
+    }
+
+    pub fn set_status(&mut self, status: RaceStatus) {
+        self.status = status as u8;
+    }
+
+    pub fn authority(&self) -> Pubkey {
+        Pubkey::new_from_array(self.authority)
+    }
+
+    pub fn fee_mint(&self) -> Option<Pubkey> {
+        optional_key(self.fee_mint)
+    }
+
+    pub fn oracle(&self) -> Option<Pubkey> {
+        optional_key(self.oracle)
+    }
+
+    pub fn name(&self) -> &str {
+        std::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or_default()
+    }
+
+    pub fn set_name(&mut self, name: &str) -> ProgramResult {
+        if name.len() > MAX_NAME_LENGTH {
+            return Err(RaceError::NameTooLong.into());
+        }
+        self.name = [0; MAX_NAME_LENGTH];
+        self.name[..name.len()].copy_from_slice(name.as_bytes());
+        self.name_len = name.len() as u8;
+        Ok(())
+    }
+
+    pub fn location(&self) -> &str {
+        std::str::from_utf8(&self.location[..self.location_len as usize]).unwrap_or_default()
+    }
+
+    pub fn set_location(&mut self, location: &str) -> ProgramResult {
+        if location.len() > MAX_LOCATION_LENGTH {
+            return Err(RaceError::LocationTooLong.into());
+        }
+        self.location = [0; MAX_LOCATION_LENGTH];
+        self.location[..location.len()].copy_from_slice(location.as_bytes());
+        self.location_len = location.len() as u8;
+        Ok(())
+    }
+
+    pub fn game_url(&self) -> &str {
+        std::str::from_utf8(&self.game_url[..self.game_url_len as usize]).unwrap_or_default()
+    }
+
+    pub fn set_game_url(&mut self, game_url: &str) -> ProgramResult {
+        if game_url.len() > MAX_GAME_URL_LENGTH {
+            return Err(RaceError::GameUrlTooLong.into());
+        }
+        self.game_url = [0; MAX_GAME_URL_LENGTH];
+        self.game_url[..game_url.len()].copy_from_slice(game_url.as_bytes());
+        self.game_url_len = game_url.len() as u8;
+        Ok(())
+    }
+
+    pub fn payout_table(&self) -> &[u16] {
+        &self.payout_table[..self.payout_places as usize]
+    }
+
+    pub fn set_payout_table(&mut self, payout_table: &[u16]) -> ProgramResult {
+        assert_valid_payout_table(payout_table)?;
+        self.payout_table = [0; MAX_PAYOUT_PLACES];
+        self.payout_table[..payout_table.len()].copy_from_slice(payout_table);
+        self.payout_places = payout_table.len() as u8;
+        Ok(())
+    }
+
+    /// Treasury share of the prize pool
+    pub fn protocol_fee(&self) -> u64 {
+        (self.prize_pool as u128 * self.protocol_fee_bps as u128 / PAYOUT_BPS as u128) as u64
+    }
+
+    pub fn results(&self) -> &[u8] {
+        &self.results[..self.results_len as usize]
+    }
+
+    /// Time registration closes and the field is locked
+    pub fn lock_time(&self) -> u64 {
+        self.date.saturating_sub(self.lock_period)
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.player_count as usize
+    }
+
+    /// Start the race, or cancel it when fewer than `min_players` joined
+    pub fn start_or_cancel(&mut self) {
+        if self.player_count < self.min_players {
+            msg!("{} of {} players joined, cancelling", self.player_count, self.min_players);
+            self.set_status(RaceStatus::Cancelled);
+        } else {
+            self.set_status(RaceStatus::Running);
+        }
+    }
+
+    /// Players joined on the current terms, only the name, location and game url can change
+    pub fn terms_locked(&self) -> bool {
+        self.player_count > 0
+    }
+
+    pub fn is_slot_taken(&self, slot: u8) -> bool {
+        self.slots[slot as usize / 8] & (1 << (slot % 8)) != 0
+    }
+
+    pub fn set_slot_taken(&mut self, slot: u8, taken: bool) {
+        if taken {
+            self.slots[slot as usize / 8] |= 1 << (slot % 8);
+        } else {
+            self.slots[slot as usize / 8] &= !(1 << (slot % 8));
+        }
+    }
+
+    /// Fails unless `authority_info` is the race authority and signed the transaction
+    pub fn assert_authority(&self, authority_info: &AccountInfo) -> ProgramResult {
+        if self.authority() != *authority_info.key {
+            return Err(RaceError::AuthorityIncorrect.into());
+        }
+        if !authority_info.is_signer {
+            return Err(RaceError::AuthorityIsNotSigner.into());
+        }
+        Ok(())
+    }
+
+    /// Fails unless `settler_info` is the race authority or oracle and signed the transaction
+    pub fn assert_settler(&self, settler_info: &AccountInfo) -> ProgramResult {
+        if self.authority() != *settler_info.key && self.oracle() != Some(*settler_info.key) {
+            return Err(RaceError::SettlerIncorrect.into());
+        }
+        if !settler_info.is_signer {
+            return Err(RaceError::AuthorityIsNotSigner.into());
+        }
+        Ok(())
+    }
+}
+
+/// Ticket of a player in a race, PDA of [PREFIX, program id, race account, player]
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub struct RaceEntry {
+    pub key: u8,
+    pub slot: u8,
+    /// Entry fee returned after the race was cancelled
+    pub refunded: u8,
+    pub bump_seed: u8,
+    pub _padding: [u8; 4],
+    pub race: [u8; 32],
+    pub player: [u8; 32],
+    /// Entry fee paid when joining
+    pub fee_paid: u64,
+}
+
+impl RaceEntry {
+    /// Borrow the entry of a player in the race, failing unless `a` is that race's entry
+    pub fn from_account_info<'a>(
+        program_id: &Pubkey,
+        race_account_info: &AccountInfo,
+        a: &'a AccountInfo,
+    ) -> Result<RefMut<'a, RaceEntry>, ProgramError> {
+        if a.owner != program_id {
+            return Err(RaceError::InvalidRaceEntryKey.into());
+        }
+        let entry = RaceEntry::from_account_info_unchecked(a)?;
+        if entry.key != Key::RaceEntry as u8 {
+            return Err(RaceError::DataTypeMismatch.into());
+        }
+        if entry.race != race_account_info.key.to_bytes() {
+            return Err(RaceError::InvalidRaceEntryKey.into());
+        }
+        assert_race_entry(program_id, race_account_info, &entry.player(), a)?;
+        Ok(entry)
+    }
+
+    pub fn from_account_info_unchecked<'a>(
+        a: &'a AccountInfo,
+    ) -> Result<RefMut<'a, RaceEntry>, ProgramError> {
+        let data = a.data.borrow_mut();
+        if data.len() < RACE_ENTRY_LEN {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        bytemuck::try_from_bytes::<RaceEntry>(&data[..RACE_ENTRY_LEN])
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(RefMut::map(data, |data| {
+            bytemuck::from_bytes_mut(&mut data[..RACE_ENTRY_LEN])
+        }))
+    }
+
+    pub fn player(&self) -> Pubkey {
+        Pubkey::new_from_array(self.player)
+    }
+
+    /// Whether `a` holds a live race entry, closed entries are zeroed
+    pub fn exists(program_id: &Pubkey, a: &AccountInfo) -> Result<bool, ProgramError> {
+        if a.owner != program_id || a.data_is_empty() {
+            return Ok(false);
+        }
+        Ok(RaceEntry::from_account_info_unchecked(a)?.key != Key::Uninitialized as u8)
+    }
+}
+
+/// Program wide settings, PDA of [PREFIX, program id, CONFIG]
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub struct ProgramConfig {
+    pub key: u8,
+    pub allowed_program_count: u8,
+    pub paused: u8,
+    /// Most slots a race may have, 0 for MAX_PLAYERS
+    pub max_players: u8,
+    /// Share of the prize pool paid to the treasury by the races created from now on
+    pub protocol_fee_bps: u16,
+    pub version: u8,
+    pub _padding: u8,
+    pub admin: [u8; 32],
+    pub treasury: [u8; 32],
+    /// Highest entry fee a race may charge, 0 for no limit
+    pub max_entry_fee: u64,
+    /// Programs allowed to join and leave races on behalf of their users through CPI
+    pub allowed_programs: [[u8; 32]; MAX_ALLOWED_PROGRAMS],
+    /// Lamports AdvanceRace pays its caller, out of the race vault's surplus lamports
+    pub crank_reward: u64,
+    /// Game server key whose Ed25519 signatures SubmitResult accepts, unset disables it
+    pub result_signer: [u8; 32],
+    /// Space for new fields, configs cannot be resized
+    pub _reserved: [u8; 64],
+}
+
+impl ProgramConfig {
+    /// Borrow the program config, failing unless `a` is the config of this program
+    pub fn from_account_info<'a>(
+        program_id: &Pubkey,
+        a: &'a AccountInfo,
+    ) -> Result<RefMut<'a, ProgramConfig>, ProgramError> {
+        if a.owner != program_id || *a.key != find_config_address(program_id).0 {
+            return Err(RaceError::InvalidConfigKey.into());
+        }
+        let config = ProgramConfig::from_account_info_unchecked(a)?;
+        if config.key != Key::ProgramConfig as u8 {
+            return Err(RaceError::DataTypeMismatch.into());
+        }
+        if config.version == 0 || config.version > PROGRAM_CONFIG_VERSION {
+            return Err(RaceError::UnsupportedConfigVersion.into());
+        }
+        Ok(config)
+    }
+
+    pub fn from_account_info_unchecked<'a>(
+        a: &'a AccountInfo,
+    ) -> Result<RefMut<'a, ProgramConfig>, ProgramError> {
+        let data = a.data.borrow_mut();
+        if data.len() < PROGRAM_CONFIG_LEN {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        bytemuck::try_from_bytes::<ProgramConfig>(&data[..PROGRAM_CONFIG_LEN])
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(RefMut::map(data, |data| {
+            bytemuck::from_bytes_mut(&mut data[..PROGRAM_CONFIG_LEN])
+        }))
+    }
+
+    pub fn admin(&self) -> Pubkey {
+        Pubkey::new_from_array(self.admin)
+    }
+
+    /// Fails unless `admin_info` is the config admin and signed the transaction
+    pub fn assert_admin(&self, admin_info: &AccountInfo) -> ProgramResult {
+        if self.admin() != *admin_info.key || !admin_info.is_signer {
+            return Err(RaceError::AdminIncorrect.into());
+        }
+        Ok(())
+    }
+
+    pub fn treasury(&self) -> Pubkey {
+        Pubkey::new_from_array(self.treasury)
+    }
+
+    pub fn result_signer(&self) -> Option<Pubkey> {
+        optional_key(self.result_signer)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused != 0
+    }
+
+    pub fn set_protocol_fee_bps(&mut self, protocol_fee_bps: u16) -> ProgramResult {
+        if protocol_fee_bps > PAYOUT_BPS {
+            return Err(RaceError::InvalidProtocolFee.into());
+        }
+        self.protocol_fee_bps = protocol_fee_bps;
+        Ok(())
+    }
+
+    /// Fails unless a race with these terms stays within the config limits
+    pub fn assert_race_limits(&self, entry_fee: u64, max_players: u8) -> ProgramResult {
+        if self.max_entry_fee != 0 && entry_fee > self.max_entry_fee {
+            return Err(RaceError::EntryFeeTooHigh.into());
+        }
+        if self.max_players != 0 && max_players > self.max_players {
+            return Err(RaceError::TooManyPlayers.into());
+        }
+        Ok(())
+    }
+
+    pub fn allowed_programs(&self) -> &[[u8; 32]] {
+        &self.allowed_programs[..self.allowed_program_count as usize]
+    }
+
+    pub fn set_allowed_programs(&mut self, allowed_programs: &[Pubkey]) -> ProgramResult {
+        if allowed_programs.len() > MAX_ALLOWED_PROGRAMS {
+            return Err(RaceError::TooManyAllowedPrograms.into());
+        }
+        self.allowed_programs = [[0; 32]; MAX_ALLOWED_PROGRAMS];
+        for (allowed, program) in self.allowed_programs.iter_mut().zip(allowed_programs) {
+            *allowed = program.to_bytes();
+        }
+        self.allowed_program_count = allowed_programs.len() as u8;
+        Ok(())
+    }
+
+    pub fn is_allowed_program(&self, program: &Pubkey) -> bool {
+        self.allowed_programs().contains(&program.to_bytes())
+    }
+}
+
+/// Player table row of the version 1 and 2 race layouts, only read to migrate them
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub struct LegacyPlayer {
+    pub address: [u8; 32],
+    pub fee_paid: u64,
+    pub taken: u8,
+    pub refunded: u8,
+    pub _padding: [u8; 6],
+}
+
+/// Address of the race account created for `race_id`, and its bump seed
+pub fn find_race_address(program_id: &Pubkey, race_id: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[PREFIX.as_bytes(), program_id.as_ref(), &race_id.to_le_bytes()],
+        program_id,
+    )
+}
+
+/// Address of the vault of `race`, and its bump seed
+pub fn find_vault_address(program_id: &Pubkey, race: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[PREFIX.as_bytes(), program_id.as_ref(), race.as_ref(), VAULT.as_bytes()],
+        program_id,
+    )
+}
+
+/// Address of the race entry of `player` in `race`, and its bump seed
+pub fn find_race_entry_address(program_id: &Pubkey, race: &Pubkey, player: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[PREFIX.as_bytes(), program_id.as_ref(), race.as_ref(), player.as_ref()],
+        program_id,
+    )
+}
+
+/// Address of the program config, and its bump seed
+pub fn find_config_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[PREFIX.as_bytes(), program_id.as_ref(), CONFIG.as_bytes()],
+        program_id,
+    )
+}
+
+/// Address `caller_program` signs its CPIs into the race program with, and its bump seed
+pub fn find_cpi_authority_address(caller_program: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CPI_AUTHORITY.as_bytes()], caller_program)
+}
+
+/// Keys stored as all zeros are unset
+pub fn optional_key(key: [u8; 32]) -> Option<Pubkey> {
+    if key == [0; 32] {
+        None
+    } else {
+        Some(Pubkey::new_from_array(key))
+    }
+}
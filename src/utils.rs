@@ -0,0 +1,497 @@
+//! Account checks and helpers shared by the processors
+
+use crate::{
+    error::RaceError,
+    instruction::{ed25519_program, ED25519_HEADER_LEN, ED25519_OFFSETS_LEN},
+    state::{
+        find_race_entry_address, find_vault_address, Key, LegacyPlayer, RaceAccount, RaceEntry,
+        LEGACY_MAX_PLAYERS, LEGACY_PLAYERS_OFFSET, MAX_DISTANCE, MAX_PAYOUT_PLACES, MIN_DISTANCE,
+        PAYOUT_BPS, PREFIX, RACE_ENTRY_LEN, VAULT,
+    },
+};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    bpf_loader_upgradeable,
+    entrypoint::ProgramResult,
+    msg,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    system_instruction, system_program,
+    sysvar::{
+        clock::Clock,
+        instructions::{load_current_index, load_instruction_at},
+        rent::Rent,
+        Sysvar,
+    },
+};
+use spl_associated_token_account::get_associated_token_address;
+use std::{mem::size_of, slice::Iter};
+
+/// Split `prize_pool` between the paid places in proportion to their payout table shares,
+/// any rounding remainder goes to the winner
+pub fn split_prize_pool(prize_pool: u64, shares: &[u16]) -> Result<Vec<u64>, ProgramError> {
+    let total_shares: u128 = shares.iter().map(|s| *s as u128).sum();
+    if total_shares == 0 {
+        return Ok(vec![0; shares.len()]);
+    }
+    let mut payouts = shares
+        .iter()
+        .map(|share| (prize_pool as u128 * *share as u128 / total_shares) as u64)
+        .collect::<Vec<u64>>();
+    let paid: u64 = payouts.iter().sum();
+    payouts[0] = payouts[0]
+        .checked_add(prize_pool - paid)
+        .ok_or(RaceError::NumericalOverflowError)?;
+    Ok(payouts)
+}
+
+/// Race vault and, for fee mint races, its token account, used to pay out of the vault
+pub struct VaultAccounts<'a, 'b> {
+    pub vault_info: &'b AccountInfo<'a>,
+    pub bump_seed: u8,
+    pub vault_token_info: Option<&'b AccountInfo<'a>>,
+    pub token_program_info: Option<&'b AccountInfo<'a>>,
+}
+
+impl<'a, 'b> VaultAccounts<'a, 'b> {
+    /// Check the vault, taking the token accounts from `accounts_iter` when the race has a fee mint
+    pub fn load(
+        program_id: &Pubkey,
+        race_account_info: &AccountInfo<'a>,
+        race_account: &RaceAccount,
+        vault_info: &'b AccountInfo<'a>,
+        accounts_iter: &mut Iter<'b, AccountInfo<'a>>,
+    ) -> Result<Self, ProgramError> {
+        let bump_seed = assert_vault(program_id, race_account_info, vault_info)?;
+        if vault_info.owner != program_id {
+            return Err(RaceError::InvalidVaultKey.into());
+        }
+
+        let mut vault = VaultAccounts {
+            vault_info,
+            bump_seed,
+            vault_token_info: None,
+            token_program_info: None,
+        };
+        if let Some(fee_mint) = race_account.fee_mint() {
+            let vault_token_info = next_account_info(accounts_iter)?;
+            let token_program_info = next_account_info(accounts_iter)?;
+            if *token_program_info.key != spl_token::id() {
+                return Err(RaceError::InvalidTokenProgram.into());
+            }
+            if *vault_token_info.key != get_associated_token_address(vault_info.key, &fee_mint) {
+                return Err(RaceError::InvalidVaultTokenAccount.into());
+            }
+            vault.vault_token_info = Some(vault_token_info);
+            vault.token_program_info = Some(token_program_info);
+        }
+        Ok(vault)
+    }
+
+    /// Pay `amount` to `recipient` through `destination_info`, which is the recipient wallet
+    /// itself or, for fee mint races, a token account owned by the recipient
+    pub fn withdraw(
+        &self,
+        program_id: &Pubkey,
+        race_account_info: &AccountInfo<'a>,
+        race_account: &RaceAccount,
+        recipient: &Pubkey,
+        destination_info: &AccountInfo<'a>,
+        amount: u64,
+    ) -> ProgramResult {
+        match (race_account.fee_mint(), self.vault_token_info, self.token_program_info) {
+            (Some(fee_mint), Some(vault_token_info), Some(token_program_info)) => {
+                let destination = spl_token::state::Account::unpack(&destination_info.data.borrow())?;
+                if destination.owner != *recipient || destination.mint != fee_mint {
+                    return Err(RaceError::InvalidPayoutAccount.into());
+                }
+                if amount == 0 {
+                    return Ok(());
+                }
+                let vault_signer_seeds = &[
+                    PREFIX.as_bytes(),
+                    program_id.as_ref(),
+                    race_account_info.key.as_ref(),
+                    VAULT.as_bytes(),
+                    &[self.bump_seed],
+                ];
+                invoke_signed(
+                    &spl_token::instruction::transfer(
+                        token_program_info.key,
+                        vault_token_info.key,
+                        destination_info.key,
+                        self.vault_info.key,
+                        &[],
+                        amount,
+                    )?,
+                    &[
+                        vault_token_info.clone(),
+                        destination_info.clone(),
+                        self.vault_info.clone(),
+                        token_program_info.clone(),
+                    ],
+                    &[vault_signer_seeds],
+                )
+            }
+            _ => {
+                if destination_info.key != recipient {
+                    return Err(RaceError::InvalidPayoutAccount.into());
+                }
+                let vault_lamports = self.vault_info.lamports();
+                **self.vault_info.lamports.borrow_mut() = vault_lamports
+                    .checked_sub(amount)
+                    .ok_or(RaceError::NumericalOverflowError)?;
+                let destination_lamports = destination_info.lamports();
+                **destination_info.lamports.borrow_mut() = destination_lamports
+                    .checked_add(amount)
+                    .ok_or(RaceError::NumericalOverflowError)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Fails unless `entry_info` is the race entry derived from the race account and player,
+/// returns its bump seed
+pub fn assert_race_entry(
+    program_id: &Pubkey,
+    race_account_info: &AccountInfo,
+    player: &Pubkey,
+    entry_info: &AccountInfo,
+) -> Result<u8, ProgramError> {
+    let (entry_key, bump_seed) = find_race_entry_address(program_id, race_account_info.key, player);
+    if entry_key != *entry_info.key {
+        return Err(RaceError::InvalidRaceEntryKey.into());
+    }
+    Ok(bump_seed)
+}
+
+/// Create the race entry of `player` holding `slot`, paid for by `payer_info`
+#[allow(clippy::too_many_arguments)]
+pub fn create_race_entry<'a>(
+    program_id: &Pubkey,
+    race_account_info: &AccountInfo<'a>,
+    entry_info: &AccountInfo<'a>,
+    player: &Pubkey,
+    payer_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    rent_info: &AccountInfo<'a>,
+    slot: u8,
+    fee_paid: u64,
+) -> ProgramResult {
+    let bump_seed = assert_race_entry(program_id, race_account_info, player, entry_info)?;
+    if RaceEntry::exists(program_id, entry_info)? {
+        return Err(RaceError::PlayerFoundError.into());
+    }
+
+    // An entry closed earlier in the same transaction still has its data, it is reused
+    // but has to be funded again or the runtime deletes it once the transaction ends
+    if !entry_info.data_is_empty() {
+        let rent = &Rent::from_account_info(rent_info)?;
+        let required_lamports = rent
+            .minimum_balance(RACE_ENTRY_LEN)
+            .saturating_sub(entry_info.lamports());
+        if required_lamports > 0 {
+            msg!("Transfer {} lamports to the reused race entry", required_lamports);
+            invoke(
+                &system_instruction::transfer(payer_info.key, entry_info.key, required_lamports),
+                &[
+                    payer_info.clone(),
+                    entry_info.clone(),
+                    system_program_info.clone(),
+                ],
+            )?;
+        }
+    } else {
+        let entry_signer_seeds = &[
+            PREFIX.as_bytes(),
+            program_id.as_ref(),
+            race_account_info.key.as_ref(),
+            player.as_ref(),
+            &[bump_seed],
+        ];
+        create_or_allocate_account_raw(
+            *program_id,
+            entry_info,
+            rent_info,
+            system_program_info,
+            payer_info,
+            RACE_ENTRY_LEN,
+            entry_signer_seeds,
+        )?;
+    }
+
+    let mut entry = RaceEntry::from_account_info_unchecked(entry_info)?;
+    entry.key = Key::RaceEntry as u8;
+    entry.slot = slot;
+    entry.refunded = 0;
+    entry.bump_seed = bump_seed;
+    entry.race = race_account_info.key.to_bytes();
+    entry.player = player.to_bytes();
+    entry.fee_paid = fee_paid;
+    Ok(())
+}
+
+/// Close a race entry, moving its rent to `destination_info`
+pub fn close_race_entry(entry_info: &AccountInfo, destination_info: &AccountInfo) -> ProgramResult {
+    let destination_lamports = destination_info.lamports();
+    **destination_info.lamports.borrow_mut() = destination_lamports
+        .checked_add(entry_info.lamports())
+        .ok_or(RaceError::NumericalOverflowError)?;
+    **entry_info.lamports.borrow_mut() = 0;
+    entry_info.data.borrow_mut().fill(0);
+    Ok(())
+}
+
+/// Taken slots of the player table of a version 1 or 2 race account
+pub fn read_legacy_players(race_account_info: &AccountInfo) -> Result<Vec<(u8, LegacyPlayer)>, ProgramError> {
+    let data = race_account_info.data.borrow();
+    let table_len = LEGACY_MAX_PLAYERS * size_of::<LegacyPlayer>();
+    let table = data
+        .get(LEGACY_PLAYERS_OFFSET..LEGACY_PLAYERS_OFFSET + table_len)
+        .ok_or(ProgramError::AccountDataTooSmall)?;
+    let players = bytemuck::try_cast_slice::<u8, LegacyPlayer>(table)
+        .map_err(|_| ProgramError::InvalidAccountData)?;
+    Ok(players
+        .iter()
+        .enumerate()
+        .filter(|(_, player)| player.taken != 0)
+        .map(|(slot, player)| (slot as u8, *player))
+        .collect())
+}
+
+/// Size of the ProgramData header: the state variant as u32, the slot and the optional
+/// upgrade authority
+const PROGRAM_DATA_HEADER_LEN: usize = 45;
+
+/// Fails unless `upgrade_authority_info` signed and is the upgrade authority recorded in the
+/// ProgramData account of the program
+pub fn assert_upgrade_authority(
+    program_id: &Pubkey,
+    program_data_info: &AccountInfo,
+    upgrade_authority_info: &AccountInfo,
+) -> ProgramResult {
+    let (program_data_key, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    if *program_data_info.key != program_data_key
+        || *program_data_info.owner != bpf_loader_upgradeable::id()
+    {
+        return Err(RaceError::InvalidProgramData.into());
+    }
+
+    // UpgradeableLoaderState is bincode encoded, ProgramData is its fourth variant
+    let data = program_data_info.data.borrow();
+    if data.len() < PROGRAM_DATA_HEADER_LEN || data[..4] != 3u32.to_le_bytes() {
+        return Err(RaceError::InvalidProgramData.into());
+    }
+    // An immutable program has no upgrade authority, nobody can create its config
+    let upgrade_authority = if data[12] == 1 { Some(&data[13..45]) } else { None };
+    if !upgrade_authority_info.is_signer
+        || upgrade_authority != Some(upgrade_authority_info.key.as_ref())
+    {
+        return Err(RaceError::UpgradeAuthorityIncorrect.into());
+    }
+    Ok(())
+}
+
+/// Fails unless `vault_info` is the vault derived from the race account, returns its bump seed
+pub fn assert_vault(
+    program_id: &Pubkey,
+    race_account_info: &AccountInfo,
+    vault_info: &AccountInfo,
+) -> Result<u8, ProgramError> {
+    let (vault_key, bump_seed) = find_vault_address(program_id, race_account_info.key);
+    if vault_key != *vault_info.key {
+        return Err(RaceError::InvalidVaultKey.into());
+    }
+    Ok(bump_seed)
+}
+
+/// Move `amount` of the race currency from a signing wallet into the race vault,
+/// taking the token accounts from `accounts_iter` when the race has a fee mint
+pub fn deposit_to_vault<'a>(
+    race_account: &RaceAccount,
+    from_info: &AccountInfo<'a>,
+    vault_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    accounts_iter: &mut Iter<AccountInfo<'a>>,
+    amount: u64,
+) -> ProgramResult {
+    if *system_program_info.key != system_program::id() {
+        return Err(RaceError::InvalidSystemProgram.into());
+    }
+
+    if let Some(fee_mint) = race_account.fee_mint() {
+        let source_info = next_account_info(accounts_iter)?;
+        let vault_token_info = next_account_info(accounts_iter)?;
+        let token_program_info = next_account_info(accounts_iter)?;
+
+        if *token_program_info.key != spl_token::id() {
+            return Err(RaceError::InvalidTokenProgram.into());
+        }
+        if *vault_token_info.key != get_associated_token_address(vault_info.key, &fee_mint) {
+            return Err(RaceError::InvalidVaultTokenAccount.into());
+        }
+        if amount == 0 {
+            return Ok(());
+        }
+        return invoke(
+            &spl_token::instruction::transfer(
+                token_program_info.key,
+                source_info.key,
+                vault_token_info.key,
+                from_info.key,
+                &[],
+                amount,
+            )?,
+            &[
+                source_info.clone(),
+                vault_token_info.clone(),
+                from_info.clone(),
+                token_program_info.clone(),
+            ],
+        );
+    }
+
+    if amount == 0 {
+        return Ok(());
+    }
+    invoke(
+        &system_instruction::transfer(from_info.key, vault_info.key, amount),
+        &[
+            from_info.clone(),
+            vault_info.clone(),
+            system_program_info.clone(),
+        ],
+    )
+}
+
+/// Create account almost from scratch, lifted from
+/// https://github.com/solana-labs/solana-program-library/blob/7d4873c61721aca25464d42cc5ef651a7923ca79/associated-token-account/program/src/processor.rs#L51-L98
+#[inline(always)]
+pub fn create_or_allocate_account_raw<'a>(
+    program_id: Pubkey,
+    new_account_info: &AccountInfo<'a>,
+    rent_sysvar_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    payer_info: &AccountInfo<'a>,
+    size: usize,
+    signer_seeds: &[&[u8]],
+) -> ProgramResult {
+    let rent = &Rent::from_account_info(rent_sysvar_info)?;
+    let required_lamports = rent
+        .minimum_balance(size)
+        .max(1)
+        .saturating_sub(new_account_info.lamports());
+
+    if required_lamports > 0 {
+        msg!("Transfer {} lamports to the new account", required_lamports);
+        invoke(
+            &system_instruction::transfer(payer_info.key, new_account_info.key, required_lamports),
+            &[
+                payer_info.clone(),
+                new_account_info.clone(),
+                system_program_info.clone(),
+            ],
+        )?;
+    }
+
+    msg!("Allocate space for the account");
+    invoke_signed(
+        &system_instruction::allocate(new_account_info.key, size as u64),
+        &[new_account_info.clone(), system_program_info.clone()],
+        &[signer_seeds],
+    )?;
+
+    msg!("Assign the account to the owning program");
+    invoke_signed(
+        &system_instruction::assign(new_account_info.key, &program_id),
+        &[new_account_info.clone(), system_program_info.clone()],
+        &[signer_seeds],
+    )?;
+
+    Ok(())
+}
+
+/// Current unix timestamp read from the clock sysvar account
+pub fn unix_timestamp(clock_info: &AccountInfo) -> Result<u64, ProgramError> {
+    let clock = Clock::from_account_info(clock_info)?;
+    Ok(clock.unix_timestamp.max(0) as u64)
+}
+
+pub fn assert_valid_withdrawal_fee(withdrawal_fee_bps: u16) -> ProgramResult {
+    if withdrawal_fee_bps > PAYOUT_BPS {
+        return Err(RaceError::InvalidWithdrawalFee.into());
+    }
+    Ok(())
+}
+
+/// Fails unless the instruction before the current one is an Ed25519 program instruction
+/// verifying a single signature of `signer` over `message`, read from the instructions sysvar.
+/// The runtime rejects the transaction when that signature is invalid.
+pub fn assert_result_attestation(
+    instructions_info: &AccountInfo,
+    signer: &Pubkey,
+    message: &[u8],
+) -> ProgramResult {
+    if *instructions_info.key != solana_program::sysvar::instructions::id() {
+        msg!("Instructions sysvar expected");
+        return Err(RaceError::InvalidResultAttestation.into());
+    }
+    let data = instructions_info.data.borrow();
+    let current_index = load_current_index(&data) as usize;
+    if current_index == 0 {
+        msg!("No Ed25519 instruction before this one");
+        return Err(RaceError::InvalidResultAttestation.into());
+    }
+    let ed25519_index = current_index - 1;
+    let instruction = load_instruction_at(ed25519_index, &data)
+        .map_err(|_| ProgramError::InvalidAccountData)?;
+    if instruction.program_id != ed25519_program::id() || !instruction.accounts.is_empty() {
+        msg!("Instruction {} is not an Ed25519 instruction", ed25519_index);
+        return Err(RaceError::InvalidResultAttestation.into());
+    }
+
+    let ed25519_data = &instruction.data;
+    if ed25519_data.len() < ED25519_HEADER_LEN + ED25519_OFFSETS_LEN || ed25519_data[0] != 1 {
+        msg!("Exactly one signature must be verified");
+        return Err(RaceError::InvalidResultAttestation.into());
+    }
+    let offset = |i: usize| {
+        let at = ED25519_HEADER_LEN + 2 * i;
+        u16::from_le_bytes([ed25519_data[at], ed25519_data[at + 1]])
+    };
+    // Signature, public key and message must be read from the Ed25519 instruction itself
+    let is_own_data = |index: u16| index == u16::MAX || index as usize == ed25519_index;
+    if !is_own_data(offset(1)) || !is_own_data(offset(3)) || !is_own_data(offset(6)) {
+        msg!("Ed25519 instruction verifies data of another instruction");
+        return Err(RaceError::InvalidResultAttestation.into());
+    }
+    let public_key_offset = offset(2) as usize;
+    let message_offset = offset(4) as usize;
+    let message_len = offset(5) as usize;
+    let public_key = ed25519_data.get(public_key_offset..public_key_offset + 32);
+    let signed_message = ed25519_data.get(message_offset..message_offset + message_len);
+    if public_key != Some(signer.as_ref()) || signed_message != Some(message) {
+        msg!("Ed25519 instruction does not verify the result signer's result");
+        return Err(RaceError::InvalidResultAttestation.into());
+    }
+    Ok(())
+}
+
+pub fn assert_valid_distance(distance: u16) -> ProgramResult {
+    if !(MIN_DISTANCE..=MAX_DISTANCE).contains(&distance) {
+        return Err(RaceError::InvalidDistance.into());
+    }
+    Ok(())
+}
+
+pub fn assert_valid_payout_table(payout_table: &[u16]) -> ProgramResult {
+    let total: u32 = payout_table.iter().map(|s| *s as u32).sum();
+    if payout_table.len() > MAX_PAYOUT_PLACES || total != PAYOUT_BPS as u32 {
+        return Err(RaceError::InvalidPayoutTable.into());
+    }
+    Ok(())
+}
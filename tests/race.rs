@@ -0,0 +1,498 @@
+// Runs against the BPF build, the native processor cannot allocate the race accounts:
+// cargo test-bpf
+#![cfg(feature = "test-bpf")]
+
+use bytemuck::Zeroable;
+use race::{
+    error::RaceError,
+    instruction::{self, InitializeConfigArgs, InitializeRaceArgs, UpdateGameArgs, UpdateRaceArgs},
+    processor::process_instruction,
+    state::{
+        find_race_address, find_race_entry_address, find_vault_address, RaceAccount, RaceEntry,
+        RaceStatus, PAYOUT_BPS, RACE_ACCOUNT_LEN, RACE_ENTRY_LEN,
+    },
+};
+use solana_program_test::*;
+use solana_sdk::{
+    account::Account,
+    bpf_loader_upgradeable::{self, UpgradeableLoaderState},
+    instruction::{Instruction, InstructionError},
+    pubkey::Pubkey,
+    signature::{keypair_from_seed, Keypair, Signer},
+    system_instruction,
+    transaction::{Transaction, TransactionError},
+    transport::TransportError,
+};
+
+const ENTRY_FEE: u64 = 1_000_000;
+
+/// Upgrade authority recorded in the ProgramData account of the program under test
+fn upgrade_authority() -> Keypair {
+    keypair_from_seed(&[7; 32]).unwrap()
+}
+
+fn program_test(program_id: Pubkey) -> ProgramTest {
+    let mut program_test = ProgramTest::new("race", program_id, processor!(process_instruction));
+    // The program is not deployed through the upgradeable loader, stand in for its ProgramData
+    let (program_data, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    program_test.add_account(
+        program_data,
+        Account::new_data(
+            1_000_000_000,
+            &UpgradeableLoaderState::ProgramData {
+                slot: 0,
+                upgrade_authority_address: Some(upgrade_authority().pubkey()),
+            },
+            &bpf_loader_upgradeable::id(),
+        )
+        .unwrap(),
+    );
+    program_test
+}
+
+async fn process_transaction(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    instructions: &[Instruction],
+    signers: &[&Keypair],
+) -> Result<(), TransportError> {
+    let recent_blockhash = banks_client.get_recent_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(instructions, Some(&payer.pubkey()));
+    let mut all_signers = vec![payer];
+    all_signers.extend_from_slice(signers);
+    transaction.sign(&all_signers, recent_blockhash);
+    banks_client.process_transaction(transaction).await
+}
+
+fn assert_race_error(result: Result<(), TransportError>, error: RaceError) {
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+    );
+}
+
+async fn get_race(banks_client: &mut BanksClient, race: Pubkey) -> RaceAccount {
+    let account = banks_client.get_account(race).await.unwrap().unwrap();
+    let mut race_account = RaceAccount::zeroed();
+    bytemuck::bytes_of_mut(&mut race_account).copy_from_slice(&account.data[..RACE_ACCOUNT_LEN]);
+    race_account
+}
+
+async fn get_entry(banks_client: &mut BanksClient, entry: Pubkey) -> RaceEntry {
+    let account = banks_client.get_account(entry).await.unwrap().unwrap();
+    let mut race_entry = RaceEntry::zeroed();
+    bytemuck::bytes_of_mut(&mut race_entry).copy_from_slice(&account.data[..RACE_ENTRY_LEN]);
+    race_entry
+}
+
+async fn get_balance(banks_client: &mut BanksClient, address: Pubkey) -> u64 {
+    banks_client.get_balance(address).await.unwrap()
+}
+
+/// Fund a new wallet from the test payer
+async fn create_player(banks_client: &mut BanksClient, payer: &Keypair) -> Keypair {
+    let player = Keypair::new();
+    process_transaction(
+        banks_client,
+        payer,
+        &[system_instruction::transfer(
+            &payer.pubkey(),
+            &player.pubkey(),
+            1_000_000_000,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    player
+}
+
+/// Create the program config, the test payer is its admin
+async fn initialize_config(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    program_id: &Pubkey,
+    protocol_fee_bps: u16,
+    treasury: &Pubkey,
+) {
+    process_transaction(
+        banks_client,
+        payer,
+        &[instruction::initialize_config(
+            program_id,
+            &payer.pubkey(),
+            &payer.pubkey(),
+            &upgrade_authority().pubkey(),
+            InitializeConfigArgs {
+                protocol_fee_bps,
+                treasury: *treasury,
+                max_entry_fee: 10 * ENTRY_FEE,
+                max_players: 0,
+                crank_reward: 0,
+                result_signer: None,
+                allowed_programs: vec![],
+            },
+        )],
+        &[&upgrade_authority()],
+    )
+    .await
+    .unwrap();
+}
+
+fn initialize_race_args(race_id: u64, withdrawal_fee_bps: u16) -> InitializeRaceArgs {
+    InitializeRaceArgs {
+        race_id,
+        max_players: 2,
+        min_players: 1,
+        status: RaceStatus::Open,
+        level: 1,
+        r#type: 0,
+        date: i64::MAX as u64,
+        name: "Derby".to_string(),
+        location: "Sha Tin".to_string(),
+        distance: 1200,
+        entry_fee: ENTRY_FEE,
+        fee_mint: None,
+        payout_table: vec![6_000, 4_000],
+        lock_period: 0,
+        withdrawal_fee_bps,
+    }
+}
+
+fn update_race_args() -> UpdateRaceArgs {
+    UpdateRaceArgs {
+        name: Some("Derby Stakes".to_string()),
+        location: Some("Happy Valley".to_string()),
+        distance: Some(1600),
+        ..UpdateRaceArgs::default()
+    }
+}
+
+#[tokio::test]
+async fn test_race_lifecycle() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, _) = program_test(program_id).start().await;
+    let (race, _) = find_race_address(&program_id, 1);
+    let (vault, _) = find_vault_address(&program_id, &race);
+    let treasury = create_player(&mut banks_client, &payer).await;
+    let protocol_fee_bps = PAYOUT_BPS / 10;
+    initialize_config(&mut banks_client, &payer, &program_id, protocol_fee_bps, &treasury.pubkey())
+        .await;
+
+    // Create, within the config limits
+    assert_race_error(
+        process_transaction(
+            &mut banks_client,
+            &payer,
+            &[instruction::initialize_race(
+                &program_id,
+                &payer.pubkey(),
+                &payer.pubkey(),
+                InitializeRaceArgs {
+                    entry_fee: 10 * ENTRY_FEE + 1,
+                    ..initialize_race_args(1, 0)
+                },
+            )],
+            &[],
+        )
+        .await,
+        RaceError::EntryFeeTooHigh,
+    );
+    process_transaction(
+        &mut banks_client,
+        &payer,
+        &[instruction::initialize_race(
+            &program_id,
+            &payer.pubkey(),
+            &payer.pubkey(),
+            initialize_race_args(1, 0),
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    let race_account = get_race(&mut banks_client, race).await;
+    assert_eq!(race_account.authority(), payer.pubkey());
+    assert_eq!(race_account.status().unwrap(), RaceStatus::Open);
+    assert_eq!(race_account.name(), "Derby");
+    assert_eq!(race_account.payout_table(), &[6_000, 4_000]);
+    assert_eq!(race_account.protocol_fee_bps, protocol_fee_bps);
+
+    // Update
+    process_transaction(
+        &mut banks_client,
+        &payer,
+        &[instruction::update_race(
+            &program_id,
+            &race,
+            &payer.pubkey(),
+            update_race_args(),
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    let race_account = get_race(&mut banks_client, race).await;
+    assert_eq!(race_account.name(), "Derby Stakes");
+    assert_eq!(race_account.location(), "Happy Valley");
+    assert_eq!(race_account.distance, 1600);
+    assert_eq!(race_account.entry_fee, ENTRY_FEE);
+
+    // Join, collecting the entry fees in the vault
+    let player1 = create_player(&mut banks_client, &payer).await;
+    let player2 = create_player(&mut banks_client, &payer).await;
+    let vault_balance = get_balance(&mut banks_client, vault).await;
+    process_transaction(
+        &mut banks_client,
+        &payer,
+        &[instruction::join_race(&program_id, &race, &player1.pubkey(), None, 0)],
+        &[&player1],
+    )
+    .await
+    .unwrap();
+    assert_eq!(get_balance(&mut banks_client, vault).await, vault_balance + ENTRY_FEE);
+    let (entry1, _) = find_race_entry_address(&program_id, &race, &player1.pubkey());
+    let race_entry = get_entry(&mut banks_client, entry1).await;
+    assert_eq!(race_entry.player(), player1.pubkey());
+    assert_eq!(race_entry.slot, 0);
+    assert_eq!(race_entry.fee_paid, ENTRY_FEE);
+
+    assert_race_error(
+        process_transaction(
+            &mut banks_client,
+            &payer,
+            &[instruction::join_race(&program_id, &race, &player1.pubkey(), None, 1)],
+            &[&player1],
+        )
+        .await,
+        RaceError::PlayerFoundError,
+    );
+    assert_race_error(
+        process_transaction(
+            &mut banks_client,
+            &payer,
+            &[instruction::join_race(&program_id, &race, &player2.pubkey(), None, 0)],
+            &[&player2],
+        )
+        .await,
+        RaceError::SlotNotAvailableError,
+    );
+
+    process_transaction(
+        &mut banks_client,
+        &payer,
+        &[instruction::join_race(&program_id, &race, &player2.pubkey(), None, 1)],
+        &[&player2],
+    )
+    .await
+    .unwrap();
+    assert_eq!(get_balance(&mut banks_client, vault).await, vault_balance + 2 * ENTRY_FEE);
+    let race_account = get_race(&mut banks_client, race).await;
+    assert_eq!(race_account.status().unwrap(), RaceStatus::Full);
+    assert_eq!(race_account.player_count(), 2);
+    assert_eq!(race_account.prize_pool, 2 * ENTRY_FEE);
+
+    process_transaction(
+        &mut banks_client,
+        &payer,
+        &[instruction::sponsor_race(&program_id, &race, &payer.pubkey(), None, 500_000)],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(get_race(&mut banks_client, race).await.prize_pool, 2 * ENTRY_FEE + 500_000);
+
+    // The authority cannot start the race by hand, the cranks start it at the race date.
+    // The program-test clock does not move, settlement is covered by the processor tests.
+    process_transaction(
+        &mut banks_client,
+        &payer,
+        &[instruction::update_game(
+            &program_id,
+            &race,
+            &payer.pubkey(),
+            UpdateGameArgs {
+                game_url: "https://darleygo.io/game/1".to_string(),
+                end_date: 1,
+                oracle: None,
+            },
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_race_error(
+        process_transaction(
+            &mut banks_client,
+            &payer,
+            &[instruction::update_race(
+                &program_id,
+                &race,
+                &payer.pubkey(),
+                UpdateRaceArgs {
+                    status: Some(RaceStatus::Running),
+                    ..UpdateRaceArgs::default()
+                },
+            )],
+            &[],
+        )
+        .await,
+        RaceError::InvalidStatusTransition,
+    );
+    assert_race_error(
+        process_transaction(
+            &mut banks_client,
+            &payer,
+            &[instruction::check_race_start(&program_id, &race)],
+            &[],
+        )
+        .await,
+        RaceError::RaceNotStarted,
+    );
+    assert_race_error(
+        process_transaction(
+            &mut banks_client,
+            &payer,
+            &[instruction::settle_race(
+                &program_id,
+                &race,
+                &payer.pubkey(),
+                &treasury.pubkey(),
+                None,
+                vec![1, 0],
+                &[player2.pubkey(), player1.pubkey()],
+            )],
+            &[],
+        )
+        .await,
+        RaceError::InvalidStatusTransition,
+    );
+    let race_account = get_race(&mut banks_client, race).await;
+    assert_eq!(race_account.status().unwrap(), RaceStatus::Full);
+    assert_eq!(get_balance(&mut banks_client, vault).await, vault_balance + 2 * ENTRY_FEE + 500_000);
+}
+
+#[tokio::test]
+async fn test_cancel_and_refund() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, _) = program_test(program_id).start().await;
+    let (race, _) = find_race_address(&program_id, 2);
+    initialize_config(&mut banks_client, &payer, &program_id, 0, &payer.pubkey()).await;
+    let authority = create_player(&mut banks_client, &payer).await;
+    let player = create_player(&mut banks_client, &payer).await;
+
+    process_transaction(
+        &mut banks_client,
+        &payer,
+        &[
+            instruction::initialize_race(
+                &program_id,
+                &payer.pubkey(),
+                &payer.pubkey(),
+                initialize_race_args(2, 0),
+            ),
+            instruction::set_authority(&program_id, &race, &payer.pubkey(), &authority.pubkey()),
+            instruction::join_race(&program_id, &race, &player.pubkey(), None, 1),
+        ],
+        &[&player],
+    )
+    .await
+    .unwrap();
+
+    assert_race_error(
+        process_transaction(
+            &mut banks_client,
+            &payer,
+            &[instruction::cancel_race(&program_id, &race, &payer.pubkey())],
+            &[],
+        )
+        .await,
+        RaceError::AuthorityIncorrect,
+    );
+    process_transaction(
+        &mut banks_client,
+        &payer,
+        &[instruction::cancel_race(&program_id, &race, &authority.pubkey())],
+        &[&authority],
+    )
+    .await
+    .unwrap();
+
+    let player_balance = get_balance(&mut banks_client, player.pubkey()).await;
+    process_transaction(
+        &mut banks_client,
+        &payer,
+        &[instruction::claim_refund(&program_id, &race, &player.pubkey(), None)],
+        &[&player],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        get_balance(&mut banks_client, player.pubkey()).await,
+        player_balance + ENTRY_FEE
+    );
+    assert_eq!(get_race(&mut banks_client, race).await.prize_pool, 0);
+
+    // The player pays the fees of the second claim so it is a different transaction
+    assert_race_error(
+        process_transaction(
+            &mut banks_client,
+            &player,
+            &[instruction::claim_refund(&program_id, &race, &player.pubkey(), None)],
+            &[],
+        )
+        .await,
+        RaceError::RefundAlreadyClaimed,
+    );
+}
+
+#[tokio::test]
+async fn test_leave_race() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, _) = program_test(program_id).start().await;
+    let (race, _) = find_race_address(&program_id, 3);
+    initialize_config(&mut banks_client, &payer, &program_id, 0, &payer.pubkey()).await;
+    let player = create_player(&mut banks_client, &payer).await;
+    let withdrawal_fee_bps = PAYOUT_BPS / 10;
+
+    process_transaction(
+        &mut banks_client,
+        &payer,
+        &[
+            instruction::initialize_race(
+                &program_id,
+                &payer.pubkey(),
+                &payer.pubkey(),
+                initialize_race_args(3, withdrawal_fee_bps),
+            ),
+            instruction::join_race(&program_id, &race, &player.pubkey(), None, 0),
+        ],
+        &[&player],
+    )
+    .await
+    .unwrap();
+
+    let (entry, _) = find_race_entry_address(&program_id, &race, &player.pubkey());
+    let entry_rent = get_balance(&mut banks_client, entry).await;
+    let player_balance = get_balance(&mut banks_client, player.pubkey()).await;
+    process_transaction(
+        &mut banks_client,
+        &payer,
+        &[instruction::leave_race(&program_id, &race, &player.pubkey(), None)],
+        &[&player],
+    )
+    .await
+    .unwrap();
+
+    let refund = ENTRY_FEE - ENTRY_FEE / 10;
+    assert_eq!(
+        get_balance(&mut banks_client, player.pubkey()).await,
+        player_balance + refund + entry_rent
+    );
+    assert!(banks_client.get_account(entry).await.unwrap().is_none());
+    let race_account = get_race(&mut banks_client, race).await;
+    assert_eq!(race_account.player_count(), 0);
+    assert!(!race_account.is_slot_taken(0));
+    assert_eq!(race_account.prize_pool, ENTRY_FEE - refund);
+}